@@ -0,0 +1,184 @@
+//! Command aliases, from the `[cargo-shell.aliases]` table of the cargo config and the `alias`
+//! built-in.
+//!
+//! An alias replaces the first word of a command with a command line of its own, which can be a
+//! special command like `+test` or `~run`. In that command line, `$1` to `$9` stand for the
+//! arguments given to the alias and `$@` for all of them; without any of those, the arguments are
+//! added to the end. Like in `sh`, an alias is not expanded again inside its own expansion, so
+//! `test = "test --all"` works, and aliases that refer to each other can't loop forever.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+
+use cargo::util::Config as CargoConfig;
+use cargo::util::config::ConfigValue;
+
+use errors::*;
+use lexer;
+
+#[derive(Default)]
+pub struct Aliases {
+    /// The aliases from the cargo configuration
+    configured: BTreeMap<String, String>,
+    /// Aliases defined with `alias`, or hidden with `unalias` when they are `None`
+    session: BTreeMap<String, Option<String>>,
+}
+
+impl Aliases {
+    /// Reads the `cargo-shell.aliases` table. Each alias is either a command line, or a list of
+    /// words like cargo's own `[alias]` table allows.
+    pub fn load(cconfig: &CargoConfig) -> Result<Aliases> {
+        let mut aliases = Aliases::default();
+        aliases.reload(cconfig)?;
+        Ok(aliases)
+    }
+
+    /// Re-reads the aliases from the cargo configuration, keeping the ones defined in the session.
+    pub fn reload(&mut self, cconfig: &CargoConfig) -> Result<()> {
+        let table = cconfig.get_table("cargo-shell.aliases").chain_err(|| "Could not get cargo-shell.aliases value")?;
+        let mut configured = BTreeMap::new();
+        for (name, value) in table.map(|t| t.val).unwrap_or_default() {
+            let body = match value {
+                ConfigValue::String(body, _) => body,
+                ConfigValue::List(words, _) => {
+                    words.iter().map(|&(ref w, _)| lexer::quote(w)).collect::<Vec<_>>().join(" ")
+                },
+                _ => bail!("cargo-shell.aliases.{} must be a string or a list of strings", name),
+            };
+            configured.insert(name, body);
+        }
+        self.configured = configured;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self.session.get(name) {
+            Some(&Some(ref body)) => Some(body),
+            Some(&None) => None,
+            None => self.configured.get(name).map(|b| &b[..]),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, body: &str) {
+        self.session.insert(name.into(), Some(body.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if self.get(name).is_none() {
+            bail!("No such alias: {}", name);
+        }
+        self.session.insert(name.into(), None);
+        Ok(())
+    }
+
+    /// The names of all of the aliases, in order.
+    pub fn names(&self) -> Vec<String> {
+        let names = self.configured.keys()
+                                   .chain(self.session.keys())
+                                   .filter(|name| self.get(name).is_some())
+                                   .cloned()
+                                   .collect::<BTreeSet<_>>();
+        names.into_iter().collect()
+    }
+
+    pub fn print(&self) {
+        for name in self.names() {
+            println!("{} = {}", name, self.get(&name).unwrap_or_default());
+        }
+    }
+
+    /// Moves the session's aliases over from `other`, for when the shell moves to another project.
+    pub fn take_session(&mut self, other: &mut Aliases) {
+        mem::swap(&mut self.session, &mut other.session);
+    }
+}
+
+/// Puts the `args` given to an alias into its command line.
+pub fn substitute(body: &str, args: &[String]) -> String {
+    let all = args.iter().map(|a| lexer::quote(a)).collect::<Vec<_>>().join(" ");
+    let mut line = String::new();
+    let mut placeholders = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' {
+            match chars.peek().cloned() {
+                Some('@') => {
+                    chars.next();
+                    line.push_str(&all);
+                    placeholders = true;
+                    continue;
+                },
+                Some(d) if d >= '1' && d <= '9' => {
+                    chars.next();
+                    let n = d.to_digit(10).unwrap_or(1) as usize;
+                    if let Some(arg) = args.get(n - 1) {
+                        line.push_str(&lexer::quote(arg));
+                    }
+                    placeholders = true;
+                    continue;
+                },
+                _ => {},
+            }
+        }
+        line.push(c);
+    }
+    if !placeholders && !all.is_empty() {
+        line.push(' ');
+        line.push_str(&all);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments_are_appended() {
+        assert_eq!(substitute("test --all", &[]), "test --all");
+        assert_eq!(substitute("test", &args(&["--release", "it's"])), "test --release 'it'\\''s'");
+    }
+
+    #[test]
+    fn numbered_placeholders() {
+        assert_eq!(substitute("run --bin $1 -- $2", &args(&["server", "a b"])), "run --bin server -- 'a b'");
+        assert_eq!(substitute("run $2 $1", &args(&["a", "b", "c"])), "run b a");
+        // missing arguments are left out
+        assert_eq!(substitute("run $1 $3", &args(&["a"])), "run a ");
+        // only a single digit is a placeholder
+        assert_eq!(substitute("run $10", &args(&["a"])), "run a0");
+    }
+
+    #[test]
+    fn all_arguments() {
+        assert_eq!(substitute("test $@ -- --nocapture", &args(&["a b", "c"])), "test 'a b' c -- --nocapture");
+        assert_eq!(substitute("test $@", &[]), "test ");
+    }
+
+    #[test]
+    fn other_dollars_are_left_alone() {
+        assert_eq!(substitute("run $HOME $0 $", &args(&["x"])), "run $HOME $0 $ x");
+        assert_eq!(substitute("run ${1}", &[]), "run ${1}");
+    }
+
+    #[test]
+    fn session_aliases() {
+        let mut aliases = Aliases::default();
+        aliases.configured.insert("t".into(), "test --all".into());
+        aliases.configured.insert("b".into(), "build".into());
+        aliases.set("r", "run");
+        aliases.set("b", "build --release");
+        assert_eq!(aliases.get("t"), Some("test --all"));
+        assert_eq!(aliases.get("b"), Some("build --release"));
+        assert_eq!(aliases.names(), ["b", "r", "t"]);
+
+        aliases.remove("t").unwrap();
+        assert_eq!(aliases.get("t"), None);
+        assert!(aliases.remove("t").is_err());
+        assert_eq!(aliases.names(), ["b", "r"]);
+    }
+}
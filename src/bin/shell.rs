@@ -2,15 +2,45 @@ extern crate shell;
 extern crate env_logger;
 #[macro_use] extern crate error_chain;
 
+use std::env;
 use std::io::{self, Write};
 use std::process;
 use std::error::Error as StdError;
 
+const USAGE: &'static str = "Usage: cargo shell [-c <command> | --script <file>]
+
+Without any arguments, the shell is interactive when it is started in a terminal. Otherwise it
+runs the commands it reads from standard input, like a script.";
+
 fn main() {
     env_logger::init().unwrap();
-    if let Err(e) = shell::main() {
-        let _ = writeln!(&mut io::stderr(), "{}", e.description());
-        process::exit(1);
+
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    // `cargo shell` runs us as `cargo-shell shell ...`
+    if args.first().map(|a| a == "shell").unwrap_or(false) {
+        args.remove(0);
     }
-}
 
+    let result = match args.first().map(|a| &a[..]) {
+        None if shell::is_interactive() => shell::main().map(|_| 0),
+        None => shell::stdin(),
+        Some("-c") if args.len() == 2 => shell::command(&args[1]),
+        Some("--script") if args.len() == 2 => shell::script(&args[1]),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(0)
+        },
+        _ => {
+            let _ = writeln!(&mut io::stderr(), "{}", USAGE);
+            process::exit(1);
+        },
+    };
+
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}", e.description());
+            process::exit(1);
+        },
+    }
+}
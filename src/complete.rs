@@ -0,0 +1,273 @@
+//! Tab completion for the prompt.
+//!
+//! The first word of a command completes to cargo subcommands (built-in ones, and any `cargo-*`
+//! binaries on the `PATH`) and shell built-ins. After that, flags are completed for the subcommand
+//! being run, target names are completed after `--bin`, `--example`, `--test` and `--bench`, and
+//! package names after `-p`/`--package`. The word after `++` completes to toolchain names, and the
+//! word after `use` to workspace members. Aliases complete along with the subcommands. `toolchain`
+//! completes its own subcommands, and toolchain names after `toolchain install` and
+//! `toolchain pin`.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use cargo::core::Workspace;
+use cargo::util::Config as CargoConfig;
+use rustyline;
+use rustyline::completion::Completer;
+
+use errors::*;
+use toolchain;
+
+const CARGO_SUBCOMMANDS: &'static [&'static str] = &[
+    "bench", "build", "check", "clean", "doc", "fetch", "generate-lockfile", "git-checkout", "help",
+    "init", "install", "locate-project", "login", "metadata", "new", "owner", "package", "pkgid",
+    "publish", "read-manifest", "run", "rustc", "rustdoc", "search", "test", "uninstall", "update",
+    "verify-project", "version", "yank",
+];
+
+pub const BUILTINS: &'static [&'static str] = &[
+    "alias", "cd", "dirs", "do", "env", "exit", "fg", "help", "history", "jobs", "kill", "members",
+    "msrv", "popd", "pushd", "pwd", "quit", "reload", "set", "toolchain", "unalias", "unset", "use",
+];
+
+const TOOLCHAIN_SUBCOMMANDS: &'static [&'static str] = &[
+    "add-component", "add-target", "install", "list", "pin",
+];
+
+const COMMON_FLAGS: &'static [&'static str] = &[
+    "--color", "--frozen", "--help", "--locked", "--manifest-path", "--quiet", "--verbose",
+];
+
+const BUILD_FLAGS: &'static [&'static str] = &[
+    "--all-features", "--bench", "--benches", "--bin", "--bins", "--example", "--examples",
+    "--features", "--jobs", "--lib", "--no-default-features", "--package", "--release", "--target",
+    "--test", "--tests",
+];
+
+/// Flags for each subcommand, on top of `COMMON_FLAGS`.
+fn subcommand_flags(subcommand: &str) -> &'static [&'static str] {
+    match subcommand {
+        "build" | "check" | "rustc" | "rustdoc" => BUILD_FLAGS,
+        "test" => &[
+            "--all-features", "--bench", "--benches", "--bin", "--bins", "--doc", "--example",
+            "--examples", "--features", "--jobs", "--lib", "--no-default-features", "--no-fail-fast",
+            "--no-run", "--package", "--release", "--target", "--test", "--tests",
+        ],
+        "bench" => &[
+            "--all-features", "--bench", "--benches", "--bin", "--bins", "--example", "--examples",
+            "--features", "--jobs", "--lib", "--no-default-features", "--no-run", "--package",
+            "--target", "--test", "--tests",
+        ],
+        "run" => &[
+            "--all-features", "--bin", "--example", "--features", "--jobs", "--no-default-features",
+            "--package", "--release", "--target",
+        ],
+        "doc" => &[
+            "--all-features", "--bin", "--features", "--jobs", "--lib", "--no-default-features",
+            "--no-deps", "--open", "--package", "--release", "--target",
+        ],
+        "clean" => &["--package", "--release", "--target"],
+        "update" => &["--aggressive", "--package", "--precise"],
+        "new" | "init" => &["--bin", "--lib", "--name", "--vcs"],
+        "install" => &[
+            "--bin", "--branch", "--debug", "--example", "--features", "--force", "--git", "--path",
+            "--rev", "--root", "--tag", "--vers",
+        ],
+        "publish" | "package" => &["--allow-dirty", "--dry-run", "--no-verify", "--token"],
+        _ => &[],
+    }
+}
+
+/// Everything that can be completed, shared between the completer and the shell's `Config`.
+#[derive(Default)]
+pub struct Completions {
+    pub subcommands: Vec<String>,
+    pub bins: Vec<String>,
+    pub examples: Vec<String>,
+    pub tests: Vec<String>,
+    pub benches: Vec<String>,
+    pub packages: Vec<String>,
+    pub members: Vec<String>,
+    pub toolchains: Vec<String>,
+    /// Kept up to date by the shell as aliases are defined
+    pub aliases: Vec<String>,
+}
+
+impl Completions {
+    pub fn load(cconfig: &CargoConfig, manifest: Option<&Path>, rustup: &Path, toolchains: &[String]) -> Result<Completions> {
+        let mut completions = Completions::default();
+
+        let mut subcommands = CARGO_SUBCOMMANDS.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>();
+        subcommands.extend(external_subcommands());
+        completions.subcommands = subcommands.into_iter().collect();
+
+        if let Some(manifest) = manifest {
+            completions.load_workspace(cconfig, manifest)?;
+        }
+
+        let mut names = toolchains.iter().cloned().collect::<BTreeSet<_>>();
+        match toolchain::installed(rustup) {
+            Ok(installed) => {
+                for name in installed {
+                    names.insert(toolchain::short_name(&name).to_string());
+                    names.insert(name);
+                }
+            },
+            Err(e) => debug!("could not list installed toolchains: {}", e),
+        }
+        completions.toolchains = names.into_iter().collect();
+
+        Ok(completions)
+    }
+
+    /// Reads package and target names from the members of the workspace.
+    fn load_workspace(&mut self, cconfig: &CargoConfig, manifest: &Path) -> Result<()> {
+        let ws = Workspace::new(manifest, cconfig).chain_err(|| "Could not load workspace")?;
+        let mut packages = BTreeSet::new();
+        for pkg in ws.members() {
+            self.members.push(pkg.name().to_string());
+            packages.insert(pkg.name().to_string());
+            packages.extend(pkg.dependencies().iter().map(|d| d.name().to_string()));
+            for target in pkg.targets() {
+                let list = if target.is_bin() {
+                    &mut self.bins
+                } else if target.is_example() {
+                    &mut self.examples
+                } else if target.is_test() {
+                    &mut self.tests
+                } else if target.is_bench() {
+                    &mut self.benches
+                } else {
+                    continue;
+                };
+                list.push(target.name().to_string());
+            }
+        }
+        self.packages = packages.into_iter().collect();
+        self.members.sort();
+        Ok(())
+    }
+}
+
+/// Finds `cargo-*` binaries on the `PATH`, which cargo runs as subcommands.
+fn external_subcommands() -> Vec<String> {
+    let mut subcommands = Vec::new();
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return subcommands,
+    };
+    for dir in env::split_paths(&path) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with("cargo-") && name.ends_with(env::consts::EXE_SUFFIX) {
+                let end = name.len() - env::consts::EXE_SUFFIX.len();
+                subcommands.push(name["cargo-".len()..end].to_string());
+            }
+        }
+    }
+    subcommands
+}
+
+pub struct ShellCompleter {
+    completions: Rc<RefCell<Completions>>,
+}
+
+impl ShellCompleter {
+    pub fn new(completions: Rc<RefCell<Completions>>) -> ShellCompleter {
+        ShellCompleter {
+            completions: completions,
+        }
+    }
+
+    fn candidates(&self, words: &[&str], word: &str) -> Vec<String> {
+        let completions = self.completions.borrow();
+
+        // special command prefixes are completed along with the word they are attached to
+        let mut words = words;
+        if words.first() == Some(&"++") {
+            if words.len() == 1 {
+                return matching(&completions.toolchains, word, "");
+            }
+            words = &words[2..];
+        } else if words.is_empty() && word.starts_with("++") {
+            return matching(&completions.toolchains, &word[2..], "++");
+        } else if words.first().map(|w| w.starts_with("++")).unwrap_or(false) {
+            words = &words[1..];
+        } else if words.first() == Some(&"+") || words.first() == Some(&"~") {
+            words = &words[1..];
+        }
+
+        if words == ["use"] {
+            return matching(&completions.members, word, "");
+        }
+        if words == ["alias"] || words == ["unalias"] {
+            return matching(&completions.aliases, word, "");
+        }
+        if words == ["toolchain"] {
+            let subcommands = TOOLCHAIN_SUBCOMMANDS.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            return matching(&subcommands, word, "");
+        }
+        if words == ["toolchain", "install"] || words == ["toolchain", "pin"] {
+            return matching(&completions.toolchains, word, "");
+        }
+
+        if words.is_empty() {
+            let prefix = if word.starts_with("+") || word.starts_with("~") { &word[..1] } else { "" };
+            let mut all = completions.subcommands.clone();
+            if prefix.is_empty() {
+                all.extend(BUILTINS.iter().map(|s| s.to_string()));
+                all.extend(completions.aliases.iter().cloned());
+                all.sort();
+                all.dedup();
+            }
+            return matching(&all, &word[prefix.len()..], prefix);
+        }
+
+        let subcommand = words[0].trim_matches(|c| c == '+' || c == '~');
+        match words.last().cloned() {
+            Some("--bin") => return matching(&completions.bins, word, ""),
+            Some("--example") => return matching(&completions.examples, word, ""),
+            Some("--test") => return matching(&completions.tests, word, ""),
+            Some("--bench") => return matching(&completions.benches, word, ""),
+            Some("-p") | Some("--package") => return matching(&completions.packages, word, ""),
+            _ => {},
+        }
+
+        if word.starts_with("-") {
+            let mut flags = subcommand_flags(subcommand).iter()
+                                                        .chain(COMMON_FLAGS)
+                                                        .map(|s| s.to_string())
+                                                        .collect::<Vec<_>>();
+            flags.sort();
+            return matching(&flags, word, "");
+        }
+
+        Vec::new()
+    }
+}
+
+/// The entries of `list` that start with `word`, with `prefix` put back in front of them.
+fn matching(list: &[String], word: &str, prefix: &str) -> Vec<String> {
+    list.iter()
+        .filter(|s| s.starts_with(word))
+        .map(|s| format!("{}{}", prefix, s))
+        .collect()
+}
+
+impl Completer for ShellCompleter {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let words = line[..start].split_whitespace().collect::<Vec<_>>();
+        Ok((start, self.candidates(&words, &line[start..])))
+    }
+}
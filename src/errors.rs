@@ -1 +1,8 @@
-error_chain!{ }
+error_chain!{
+    errors {
+        IncompleteInput(reason: String) {
+            description("incomplete input")
+            display("incomplete input: {}", reason)
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Git information for the prompt.
+//!
+//! Everything here comes from the local repository, so it never touches the network. It is
+//! computed once after each command and cached in `Config`, rather than every time the prompt is
+//! drawn.
+
+use std::path::Path;
+
+use git2::{BranchType, Repository, StatusOptions};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GitStatus {
+    /// The checked out branch, or empty if `HEAD` is detached
+    pub branch: String,
+    /// The abbreviated hash of the `HEAD` commit
+    pub commit: String,
+    /// Whether there are uncommitted changes or untracked files
+    pub dirty: bool,
+    /// How many commits the branch is ahead of its upstream, if it has one
+    pub ahead: Option<usize>,
+    /// How many commits the branch is behind its upstream, if it has one
+    pub behind: Option<usize>,
+}
+
+impl GitStatus {
+    /// Reads the status of the repository containing `dir`, or returns `None` if there isn't one.
+    pub fn read(dir: &Path) -> Option<GitStatus> {
+        let repo = match Repository::discover(dir) {
+            Ok(repo) => repo,
+            Err(_) => return None,
+        };
+
+        let mut status = GitStatus::default();
+        let head = match repo.head() {
+            Ok(head) => head,
+            // a brand new repository doesn't have any commits yet
+            Err(_) => return Some(status),
+        };
+
+        if head.is_branch() {
+            status.branch = head.shorthand().unwrap_or("").into();
+        }
+        if let Some(oid) = head.target() {
+            status.commit = oid.to_string().chars().take(7).collect();
+        }
+
+        if !repo.is_bare() {
+            let mut opts = StatusOptions::new();
+            opts.include_untracked(true).include_ignored(false);
+            status.dirty = match repo.statuses(Some(&mut opts)) {
+                Ok(statuses) => !statuses.is_empty(),
+                Err(e) => {
+                    debug!("could not get git status: {}", e);
+                    false
+                },
+            };
+        }
+
+        if !status.branch.is_empty() {
+            // the upstream borrows from the local branch, so that has to outlive it
+            let branch = repo.find_branch(&status.branch, BranchType::Local).ok();
+            let upstream = branch.as_ref()
+                                 .and_then(|b| b.upstream().ok())
+                                 .and_then(|u| u.get().target());
+            if let (Some(local), Some(upstream)) = (head.target(), upstream) {
+                if let Ok((ahead, behind)) = repo.graph_ahead_behind(local, upstream) {
+                    status.ahead = Some(ahead);
+                    status.behind = Some(behind);
+                }
+            }
+        }
+
+        Some(status)
+    }
+}
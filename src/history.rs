@@ -0,0 +1,221 @@
+//! Command history that is saved between sessions.
+//!
+//! Besides what rustyline gives us for scrolling through old commands, the history can be used to
+//! re-run commands with `!!` (the previous command), `!n` (command number `n` from `history`),
+//! `!-n` (the `n`th previous command), `!prefix` (the most recent command starting with `prefix`)
+//! and `!?str` (the most recent command containing `str`), like in bash. Anything after the first
+//! word is appended to the expanded command, so `!! --release` re-runs the previous command with
+//! `--release` added.
+//!
+//! Also like in bash, a `!` followed by whitespace is not a history reference. The shell uses that
+//! for commands for the system shell, like `! git status`.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use errors::*;
+
+pub const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+pub struct History {
+    path: PathBuf,
+    entries: Vec<String>,
+    max_len: usize,
+}
+
+impl History {
+    /// Loads the history saved at `path`, which doesn't have to exist yet.
+    pub fn load(path: PathBuf, max_len: usize) -> Result<History> {
+        let mut history = History {
+            path: path,
+            entries: Vec::new(),
+            max_len: max_len,
+        };
+        if history.path.exists() {
+            let file = File::open(&history.path).chain_err(|| format!("Could not open history file {}", history.path.display()))?;
+            for line in BufReader::new(file).lines() {
+                let line = line.chain_err(|| "Could not read from history file")?;
+                history.add(&line);
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Adds `line` to the end of the history, removing any older copies of it.
+    pub fn add(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.contains('\n') {
+            return;
+        }
+        self.entries.retain(|e| e != line);
+        self.entries.push(line.into());
+        if self.entries.len() > self.max_len {
+            let extra = self.entries.len() - self.max_len;
+            self.entries.drain(..extra);
+        }
+    }
+
+    /// Replaces the newest entry, which is used to record what a `!` command expanded to.
+    pub fn replace_last(&mut self, line: &str) {
+        self.entries.pop();
+        self.add(line);
+    }
+
+    /// Whether `line` starts with a history reference, rather than being a `! <command>` for the
+    /// system shell.
+    pub fn is_reference(line: &str) -> bool {
+        line.starts_with('!') && line[1..].chars().next().map(|c| !c.is_whitespace()).unwrap_or(false)
+    }
+
+    /// Expands a `!` history reference at the start of `line`. The newest entry is taken to be
+    /// `line` itself, and is never the result of the expansion.
+    pub fn expand(&self, line: &str) -> Result<String> {
+        let (designator, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[1..i], &line[i..]),
+            None => (&line[1..], ""),
+        };
+        let previous = match self.entries.split_last() {
+            Some((_, previous)) => previous,
+            None => &[],
+        };
+
+        let number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_digit(10));
+        let found = if designator == "!" {
+            previous.last()
+        } else if designator.starts_with('-') && number(&designator[1..]) {
+            match designator[1..].parse::<usize>() {
+                Ok(n) if n > 0 && n <= previous.len() => previous.get(previous.len() - n),
+                _ => None,
+            }
+        } else if number(designator) {
+            match designator.parse::<usize>() {
+                Ok(n) if n > 0 => previous.get(n - 1),
+                _ => None,
+            }
+        } else if designator.starts_with('?') {
+            // like bash, a closing `?` is allowed
+            let search = designator[1..].trim_end_matches('?');
+            if search.is_empty() {
+                None
+            } else {
+                previous.iter().rev().find(|e| e.contains(search))
+            }
+        } else {
+            previous.iter().rev().find(|e| e.starts_with(designator))
+        };
+
+        match found {
+            Some(entry) => Ok(format!("{}{}", entry, rest)),
+            None => bail!("!{}: event not found", designator),
+        }
+    }
+
+    /// Writes the history out to its file, creating the directory it lives in if needed.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).chain_err(|| format!("Could not create history directory {}", dir.display()))?;
+        }
+        let mut file = File::create(&self.path).chain_err(|| format!("Could not write history file {}", self.path.display()))?;
+        for entry in &self.entries {
+            writeln!(file, "{}", entry).chain_err(|| "Could not write to history file")?;
+        }
+        Ok(())
+    }
+
+    /// Prints the history, numbered so that entries can be re-run with `!n`.
+    pub fn print(&self) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            println!("{:5}  {}", i + 1, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history with `entries` in it, followed by `line` as the newest entry, the way the shell
+    /// adds a line before running it.
+    fn history(entries: &[&str], line: &str) -> History {
+        let mut history = History::load(PathBuf::from("/nonexistent/cargo-shell-history"), 100).unwrap();
+        for entry in entries {
+            history.add(entry);
+        }
+        history.add(line);
+        history
+    }
+
+    fn expand(line: &str) -> Result<String> {
+        history(&["build", "test --all", "run --bin server", "check"], line).expand(line)
+    }
+
+    #[test]
+    fn previous_command() {
+        assert_eq!(expand("!!").unwrap(), "check");
+        assert_eq!(expand("!! --release").unwrap(), "check --release");
+        assert!(history(&[], "!!").expand("!!").is_err());
+    }
+
+    #[test]
+    fn numbered() {
+        assert_eq!(expand("!1").unwrap(), "build");
+        assert_eq!(expand("!4").unwrap(), "check");
+        assert!(expand("!0").is_err());
+        assert!(expand("!5").is_err());
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(expand("!-1").unwrap(), "check");
+        assert_eq!(expand("!-3").unwrap(), "test --all");
+        assert!(expand("!-0").is_err());
+        assert!(expand("!-5").is_err());
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(expand("!te").unwrap(), "test --all");
+        assert_eq!(expand("!r --release").unwrap(), "run --bin server --release");
+        assert_eq!(expand("!b").unwrap(), "build");
+        // not a number, so it's a prefix
+        assert!(expand("!1a").is_err());
+        assert!(expand("!all").is_err());
+    }
+
+    #[test]
+    fn search() {
+        // like bash, this finds commands containing the string, not just starting with it
+        assert_eq!(expand("!?all").unwrap(), "test --all");
+        assert_eq!(expand("!?bin").unwrap(), "run --bin server");
+        assert_eq!(expand("!?e").unwrap(), "check");
+        assert_eq!(expand("!?serv? --release").unwrap(), "run --bin server --release");
+        assert!(expand("!?nothing").is_err());
+        assert!(expand("!?").is_err());
+    }
+
+    #[test]
+    fn references() {
+        for line in &["!!", "!! --release", "!3", "!-1", "!?test", "!test --all", "!-x"] {
+            assert!(History::is_reference(line), "{}", line);
+        }
+        for line in &["!", "! git status", "!\tls", "test"] {
+            assert!(!History::is_reference(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn add() {
+        let mut history = History::load(PathBuf::from("/nonexistent/cargo-shell-history"), 3).unwrap();
+        for line in &["a", "b", "  c  ", "", "multi\nline", "d", "b"] {
+            history.add(line);
+        }
+        assert_eq!(history.entries(), ["c", "d", "b"]);
+        history.replace_last("e");
+        assert_eq!(history.entries(), ["c", "d", "e"]);
+    }
+}
@@ -0,0 +1,262 @@
+//! Background jobs, started by ending a command with `&`.
+//!
+//! A background job runs in its own process group with its output captured, so it can't write
+//! over the prompt. `jobs` lists them, `fg` shows what a job has printed so far and then follows
+//! it until it finishes, and `kill` stops the job's whole process group. A notice is printed before
+//! the next prompt when a job finishes, after which the job is gone, like in `sh`. Only the last
+//! `MAX_OUTPUT` bytes or so of a job's output are kept.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use errors::*;
+use outcome::Outcome;
+use process::{self, Interrupts, KILL_WINDOW};
+use signals;
+
+/// How much of its output a background job keeps around for `fg`
+const MAX_OUTPUT: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Running,
+    Done(Outcome),
+}
+
+pub struct Job {
+    pub id: usize,
+    pub command: String,
+    pub toolchain: String,
+    pub state: State,
+    child: Child,
+    output: Arc<Mutex<Output>>,
+    /// How much of the output has already been shown with `fg`, counting what was dropped
+    shown: usize,
+    started: Instant,
+}
+
+impl Job {
+    /// Checks whether the job has finished, without waiting for it.
+    fn update(&mut self) -> Result<()> {
+        if self.state == State::Running {
+            if let Some(status) = self.child.try_wait().chain_err(|| format!("Could not check on job {}", self.id))? {
+                self.state = State::Done(Outcome::from_status(status, self.started.elapsed()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints any output that hasn't been shown yet.
+    fn show_output(&mut self) {
+        let output = self.output.lock().unwrap();
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        if self.shown < output.dropped {
+            let _ = writeln!(stdout, "[{} bytes of output not kept]", output.dropped - self.shown);
+            self.shown = output.dropped;
+        }
+        let _ = stdout.write_all(&output.bytes[self.shown - output.dropped..]);
+        let _ = stdout.flush();
+        self.shown = output.dropped + output.bytes.len();
+    }
+
+    fn signal(&self, signal: libc::c_int) -> Result<()> {
+        // the job is the leader of its own process group, so this reaches everything it started
+        if unsafe { libc::kill(-(self.child.id() as libc::pid_t), signal) } < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| format!("Could not signal job {}", self.id));
+        }
+        Ok(())
+    }
+
+    fn describe_state(&self) -> String {
+        match self.state {
+            State::Running => "Running".into(),
+            State::Done(ref outcome) if outcome.success() => "Done".into(),
+            State::Done(ref outcome) => format!("Failed ({})", outcome),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Starts `cmd` in the background, returning the id of the new job.
+    pub fn spawn(&mut self, mut cmd: Command, command: String, toolchain: String) -> Result<usize> {
+        cmd.stdin(Stdio::null())
+           .stdout(Stdio::piped())
+           .stderr(Stdio::piped());
+        process::own_process_group(&mut cmd);
+        let mut child = cmd.spawn().chain_err(|| format!("Could not start `{}`", command))?;
+
+        let output = Arc::new(Mutex::new(Output::new(MAX_OUTPUT)));
+        if let Some(stdout) = child.stdout.take() {
+            capture(stdout, output.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture(stderr, output.clone());
+        }
+
+        let id = self.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        println!("[{}] {}", id, child.id());
+        self.jobs.push(Job {
+            id: id,
+            command: command,
+            toolchain: toolchain,
+            state: State::Running,
+            child: child,
+            output: output,
+            shown: 0,
+            started: Instant::now(),
+        });
+        Ok(id)
+    }
+
+    /// Prints a notice for every job that has finished, and forgets about them.
+    pub fn notify(&mut self) {
+        for job in &mut self.jobs {
+            if let Err(e) = job.update() {
+                println!("Error: {:?}", e);
+            }
+            if job.state != State::Running {
+                println!("[{}] {}  {}", job.id, job.describe_state(), job.command);
+            }
+        }
+        self.jobs.retain(|job| job.state == State::Running);
+    }
+
+    /// Lists every job, and forgets about the ones that have finished once they are listed.
+    pub fn list(&mut self) {
+        for job in &mut self.jobs {
+            if let Err(e) = job.update() {
+                println!("Error: {:?}", e);
+            }
+            println!("[{}] {:<10} {:<10} {}", job.id, job.describe_state(), job.toolchain, job.command);
+        }
+        self.jobs.retain(|job| job.state == State::Running);
+    }
+
+    /// Shows the output of a job and follows it until it finishes. Ctrl-C interrupts the job, and a
+    /// second Ctrl-C soon after kills it. Without an `id`, this picks the most recently started job.
+    pub fn fg(&mut self, id: Option<usize>) -> Result<Outcome> {
+        let index = self.index(id)?;
+        {
+            let job = &mut self.jobs[index];
+            println!("{}", job.command);
+            let _guard = signals::catch_interrupts();
+            let mut interrupts = Interrupts::start();
+            loop {
+                job.show_output();
+                job.update()?;
+                if job.state != State::Running {
+                    break;
+                }
+                if let Some(signal) = interrupts.check() {
+                    job.signal(signal)?;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            // pick up whatever was written just before the job exited
+            thread::sleep(Duration::from_millis(50));
+            job.show_output();
+        }
+
+        let job = self.jobs.remove(index);
+        match job.state {
+            State::Done(outcome) => Ok(outcome),
+            State::Running => unreachable!(),
+        }
+    }
+
+    /// Stops a job, along with anything it started. A job that is still running `KILL_WINDOW`
+    /// after being asked to stop is killed.
+    pub fn kill(&mut self, id: usize) -> Result<()> {
+        let index = self.index(Some(id))?;
+        let mut job = self.jobs.remove(index);
+        job.update()?;
+        if job.state == State::Running {
+            job.signal(libc::SIGTERM)?;
+            let deadline = Instant::now() + KILL_WINDOW;
+            while job.state == State::Running && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+                job.update()?;
+            }
+            if job.state == State::Running {
+                job.signal(libc::SIGKILL)?;
+                let _ = job.child.wait();
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops every job that is still running, for when the shell exits.
+    pub fn kill_all(&mut self) {
+        let ids = self.jobs.iter().map(|j| j.id).collect::<Vec<_>>();
+        for id in ids {
+            let _ = self.kill(id);
+        }
+    }
+
+    fn index(&self, id: Option<usize>) -> Result<usize> {
+        let index = match id {
+            Some(id) => self.jobs.iter().position(|j| j.id == id),
+            None if self.jobs.is_empty() => None,
+            None => Some(self.jobs.len() - 1),
+        };
+        match (index, id) {
+            (Some(index), _) => Ok(index),
+            (None, Some(id)) => bail!("No such job: {}", id),
+            (None, None) => bail!("No jobs"),
+        }
+    }
+}
+
+/// Output captured from a command, which keeps at least the last `max_len` bytes of it.
+pub struct Output {
+    pub bytes: Vec<u8>,
+    /// How many bytes have been dropped from the front of `bytes`
+    pub dropped: usize,
+    max_len: usize,
+}
+
+impl Output {
+    pub fn new(max_len: usize) -> Output {
+        Output {
+            bytes: Vec::new(),
+            dropped: 0,
+            max_len: max_len,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+        // trimming only once there's twice as much as needed keeps this from copying the whole
+        // buffer around for every read
+        if self.bytes.len() > self.max_len.saturating_mul(2) {
+            let extra = self.bytes.len() - self.max_len;
+            self.bytes.drain(..extra);
+            self.dropped += extra;
+        }
+    }
+}
+
+/// Copies everything from `source` into `output` on a separate thread, which finishes when
+/// `source` is closed.
+pub fn capture<R: Read + Send + 'static>(mut source: R, output: Arc<Mutex<Output>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match source.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => output.lock().unwrap().push(&buf[..n]),
+            }
+        }
+    })
+}
@@ -0,0 +1,422 @@
+//! Splits a command line into words, roughly the way `sh` would.
+//!
+//! Supported syntax:
+//!
+//!   * words are separated by unquoted whitespace, and runs of whitespace never produce empty words
+//!   * `'single quotes'` preserve everything literally
+//!   * `"double quotes"` preserve whitespace, but still expand variables and honor `\"`, `\\`, `\$`
+//!   * a backslash outside of quotes escapes the next character
+//!   * a backslash followed by a newline is a line continuation, and is removed entirely
+//!   * `$NAME` and `${NAME}` expand to the value of the variable, or to nothing if it is unset
+//!
+//! Unlike a real shell, the value of an expanded variable is not split into multiple words.
+//!
+//! Before a line is split into words, `split_list` splits it into a list of commands at any
+//! unquoted `;`, `&&` or `||`.
+
+use errors::*;
+
+/// Splits `line` into words, expanding variables with `lookup`.
+///
+/// An unterminated quote, or a backslash at the very end of the input, results in an
+/// `ErrorKind::IncompleteInput` error, so that the caller can ask for more input.
+pub fn split<F>(line: &str, lookup: F) -> Result<Vec<String>>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // a word can be empty but still "present", for example `""`
+    let mut in_word = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(word);
+                    word = String::new();
+                    in_word = false;
+                }
+            },
+            '\\' => {
+                match chars.next() {
+                    Some('\n') => {},
+                    Some(c) => {
+                        word.push(c);
+                        in_word = true;
+                    },
+                    None => bail!(ErrorKind::IncompleteInput("trailing backslash".into())),
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!(ErrorKind::IncompleteInput("unterminated single quote".into())),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.next() {
+                                Some('\n') => {},
+                                Some(c) if c == '"' || c == '\\' || c == '$' => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                },
+                                None => bail!(ErrorKind::IncompleteInput("unterminated double quote".into())),
+                            }
+                        },
+                        Some('$') => expand(&mut chars, &mut word, &lookup)?,
+                        Some(c) => word.push(c),
+                        None => bail!(ErrorKind::IncompleteInput("unterminated double quote".into())),
+                    }
+                }
+            },
+            '$' => {
+                let before = word.len();
+                expand(&mut chars, &mut word, &lookup)?;
+                // an unquoted variable that expands to nothing doesn't create a word on its own
+                if word.len() > before {
+                    in_word = true;
+                }
+            },
+            c => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// How a command in a list depends on the one before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    /// The first command, or one after `;`, which always runs
+    Always,
+    /// After `&&`, runs only if the previous command succeeded
+    IfSuccess,
+    /// After `||`, runs only if the previous command failed
+    IfFailure,
+}
+
+/// Splits `line` into a list of commands at unquoted `;`, `&&` and `||`. The commands are returned
+/// as they were written, so that each of them can be dispatched on its own.
+pub fn split_list(line: &str) -> Result<Vec<(Connector, String)>> {
+    let mut commands = Vec::new();
+    for (operator, command) in split_unquoted(line, &[";", "&&", "||"])? {
+        let connector = match operator {
+            "&&" => Connector::IfSuccess,
+            "||" => Connector::IfFailure,
+            _ => Connector::Always,
+        };
+        let command = command.trim();
+        if command.is_empty() {
+            if connector != Connector::Always {
+                bail!("syntax error: missing command after `{}`", operator);
+            }
+            continue;
+        }
+        commands.push((connector, command.to_string()));
+    }
+    if let Some(&(Connector::IfSuccess, _)) = commands.first() {
+        bail!("syntax error: missing command before `&&`");
+    } else if let Some(&(Connector::IfFailure, _)) = commands.first() {
+        bail!("syntax error: missing command before `||`");
+    }
+    Ok(commands)
+}
+
+/// Splits `line` at unquoted commas, for `do a, b, c`.
+pub fn split_commas(line: &str) -> Result<Vec<String>> {
+    Ok(split_unquoted(line, &[","])?.into_iter()
+                                    .map(|(_, command)| command.trim().to_string())
+                                    .filter(|command| !command.is_empty())
+                                    .collect())
+}
+
+/// Splits `line` wherever one of the `operators` appears outside of quotes, returning each piece
+/// along with the operator before it (or `""` for the first piece).
+fn split_unquoted<'a>(line: &'a str, operators: &[&'static str]) -> Result<Vec<(&'static str, &'a str)>> {
+    let mut pieces = Vec::new();
+    let mut operator = "";
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {},
+            (_, '\\') => {
+                chars.next();
+            },
+            (Some(_), _) => {},
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, _) => {
+                if i < start {
+                    continue;
+                }
+                if let Some(op) = operators.iter().cloned().find(|op| line[i..].starts_with(op)) {
+                    pieces.push((operator, &line[start..i]));
+                    operator = op;
+                    start = i + op.len();
+                }
+            },
+        }
+    }
+    if quote.is_some() {
+        bail!(ErrorKind::IncompleteInput("unterminated quote".into()));
+    }
+    pieces.push((operator, &line[start..]));
+    Ok(pieces)
+}
+
+/// Splits the first word off `line` as it was written, without expanding it, and returns it along
+/// with the rest of the line.
+pub fn first_word(line: &str) -> Result<(&str, &str)> {
+    let line = line.trim_start();
+    let mut quote = None;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {},
+            (_, '\\') => {
+                chars.next();
+            },
+            (Some(_), _) => {},
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() => return Ok((&line[..i], &line[i..])),
+            (None, _) => {},
+        }
+    }
+    if quote.is_some() {
+        bail!(ErrorKind::IncompleteInput("unterminated quote".into()));
+    }
+    Ok((line, ""))
+}
+
+/// Whether `word`, as written, assigns a variable like `NAME=value`.
+pub fn is_assignment(word: &str) -> bool {
+    match word.find('=') {
+        Some(i) if i > 0 => {
+            let name = &word[..i];
+            !name.starts_with(|c: char| c.is_digit(10)) && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        },
+        _ => false,
+    }
+}
+
+/// Quotes `word` so that `split` turns it back into the same word.
+pub fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.into();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Returns `true` if `line` ends in the middle of a quote or with a line continuation.
+pub fn is_incomplete(line: &str) -> bool {
+    match split(line, |_| None) {
+        Err(Error(ErrorKind::IncompleteInput(_), _)) => true,
+        _ => false,
+    }
+}
+
+/// Expands the variable reference following a `$`, which has already been consumed.
+fn expand<I, F>(chars: &mut ::std::iter::Peekable<I>, word: &mut String, lookup: &F) -> Result<()>
+    where I: Iterator<Item=char>,
+          F: Fn(&str) -> Option<String>
+{
+    let mut name = String::new();
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => name.push(c),
+                None => bail!("unterminated variable reference `${{{}`", name),
+            }
+        }
+        if name.is_empty() {
+            bail!("empty variable reference `${{}}`");
+        }
+    } else {
+        while let Some(&c) = chars.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if name.is_empty() {
+            // a lone `$` is just a dollar sign
+            word.push('$');
+            return Ok(());
+        }
+    }
+
+    if let Some(value) = lookup(&name) {
+        word.push_str(&value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split(line, |name| match name {
+            "VAR" => Some("value".into()),
+            "SPACED" => Some("a b".into()),
+            _ => None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(words("build --release"), ["build", "--release"]);
+        assert_eq!(words("  build   --release\t"), ["build", "--release"]);
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(words(r#"'a b' "c d" e\ f"#), ["a b", "c d", "e f"]);
+        assert_eq!(words(r#"'$VAR \n "'"#), [r#"$VAR \n ""#]);
+        assert_eq!(words(r#"a'b'"c"d"#), ["abcd"]);
+    }
+
+    #[test]
+    fn escapes_in_double_quotes() {
+        assert_eq!(words(r#""\" \\ \$VAR \n""#), [r#"" \ $VAR \n"#]);
+    }
+
+    #[test]
+    fn empty_words() {
+        assert_eq!(words(r#""""#), [""]);
+        assert_eq!(words(r#"a '' "" b"#), ["a", "", "", "b"]);
+        assert_eq!(words("a  b"), ["a", "b"]);
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("build \\"));
+        assert!(is_incomplete("echo 'abc"));
+        assert!(is_incomplete("echo \"abc"));
+        assert!(is_incomplete("echo \"abc\\"));
+        assert!(is_incomplete("test 'it"));
+        assert!(!is_incomplete("test 'it'"));
+        assert!(!is_incomplete("test"));
+    }
+
+    #[test]
+    fn line_continuations() {
+        assert_eq!(words("build \\\n--release"), ["build", "--release"]);
+        assert_eq!(words("a\\\nb"), ["ab"]);
+        assert_eq!(words("\"a\\\nb\""), ["ab"]);
+        assert_eq!(words("'a\\\nb'"), ["a\\\nb"]);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(words("$VAR ${VAR}"), ["value", "value"]);
+        assert_eq!(words("pre${VAR}post"), ["prevaluepost"]);
+        assert_eq!(words("\"$VAR-x\""), ["value-x"]);
+        // values aren't split into words
+        assert_eq!(words("$SPACED"), ["a b"]);
+        // unset variables only leave an empty word behind when quoted
+        assert_eq!(words("a $UNSET b"), ["a", "b"]);
+        assert_eq!(words("\"$UNSET\""), [""]);
+        assert!(split("${}", |_| None).is_err());
+        assert!(split("${VAR", |_| None).is_err());
+        assert!(!is_incomplete("${VAR"));
+    }
+
+    #[test]
+    fn lone_dollar() {
+        assert_eq!(words("a $ b"), ["a", "$", "b"]);
+        assert_eq!(words("$"), ["$"]);
+        assert_eq!(words("\"$@\""), ["$@"]);
+    }
+
+    #[test]
+    fn lists() {
+        let list = split_list("check; test && run || build").unwrap();
+        assert_eq!(list, [(Connector::Always, "check".to_string()),
+                          (Connector::Always, "test".to_string()),
+                          (Connector::IfSuccess, "run".to_string()),
+                          (Connector::IfFailure, "build".to_string())]);
+        assert_eq!(split_list("check;").unwrap(), [(Connector::Always, "check".to_string())]);
+        assert!(split_list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quoted_operators_are_not_split() {
+        let list = split_list(r#"run -- 'a;b' "c && d" e\;f || x"#).unwrap();
+        assert_eq!(list, [(Connector::Always, r#"run -- 'a;b' "c && d" e\;f"#.to_string()),
+                          (Connector::IfFailure, "x".to_string())]);
+        assert_eq!(split_list("run -- 'a || b'").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn list_syntax_errors() {
+        assert!(split_list("&& test").is_err());
+        assert!(split_list("|| test").is_err());
+        assert!(split_list("test &&").is_err());
+        assert!(split_list("test || ; run").is_err());
+        assert!(is_incomplete("test; run 'a"));
+    }
+
+    #[test]
+    fn commas() {
+        assert_eq!(split_commas("build, test --release, run -- 'a,b'").unwrap(),
+                   ["build", "test --release", "run -- 'a,b'"]);
+        assert_eq!(split_commas(" build ,, ").unwrap(), ["build"]);
+    }
+
+    #[test]
+    fn first_words() {
+        assert_eq!(first_word("  FOO='a b' build").unwrap(), ("FOO='a b'", " build"));
+        assert_eq!(first_word("build").unwrap(), ("build", ""));
+        assert_eq!(first_word(r#"a\ b c"#).unwrap(), (r#"a\ b"#, " c"));
+        assert!(first_word("'a b").is_err());
+    }
+
+    #[test]
+    fn assignments() {
+        assert!(is_assignment("FOO=1"));
+        assert!(is_assignment("FOO_2="));
+        assert!(!is_assignment("=1"));
+        assert!(!is_assignment("2FOO=1"));
+        assert!(!is_assignment("FOO-BAR=1"));
+        assert!(!is_assignment("build"));
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        for word in &["plain", "--flag=a,b", "", "a b", "it's", "$VAR", "\\", "\"", "a\nb", "*;&"] {
+            assert_eq!(split(&quote(word), |_| Some("oops".into())).unwrap(), [word.to_string()]);
+        }
+        assert_eq!(quote("--release"), "--release");
+        assert_eq!(quote("a b"), "'a b'");
+    }
+}
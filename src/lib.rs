@@ -1,23 +1,56 @@
 extern crate rustyline;
 extern crate cargo;
+extern crate libc;
+extern crate git2;
+extern crate toml;
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate log;
 
+mod alias;
+mod complete;
 mod errors;
+mod git;
+mod history;
+mod jobs;
+mod lexer;
+mod msrv;
+mod mtime;
+mod multi;
+mod outcome;
+mod process;
+mod prompt;
+mod script;
+mod signals;
+mod toolchain;
+mod watch;
 
-use std::fs::File;
-use std::io::{stderr, Write, BufReader, BufRead};
+use std::io::{self, Write};
 use std::process::{Command, Stdio};
 use std::path::{Path, PathBuf};
 use std::env;
+use std::mem;
+use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::os::unix::ffi::OsStrExt;
 
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
-use cargo::core::Package;
+use cargo::core::Workspace;
 use cargo::util::Config as CargoConfig;
+use cargo::util::config::ConfigValue;
 use cargo::util::important_paths::{find_root_manifest_for_wd};
 
 use errors::*;
+use outcome::Outcome;
+use history::{History, DEFAULT_HISTORY_SIZE};
+use complete::{Completions, ShellCompleter};
+use git::GitStatus;
+use prompt::Template;
+use mtime::Snapshot;
+use jobs::Jobs;
+use alias::Aliases;
+use lexer::Connector;
 
 const USAGE: &'static str = r#"Cargo Command Shell
 -------------------
@@ -27,75 +60,528 @@ bring about the same result that running `cargo COMMAND` would from your regular
 
 Special commands:
 
-  * `+ <command>`
+  * `+ [--fail-fast] <command>`
     runs the command under multiple toolchains, which are defined using the `cargo-shell.toolchains`
-    configuration option
+    configuration option, and shows which toolchains passed. With `--fail-fast`, it stops at the
+    first toolchain that fails.
+  * `+& <command>`
+    Like `+`, but runs the command under all of the toolchains at the same time. Each toolchain gets
+    a target directory of its own, and its output is printed once it finishes.
   * `++ <toolchain> [<command>]`
     This runs a command under a specific toolchain. If the `<command>` is left off, then the active
-    toolchain for the shell is changed.
+    toolchain for the shell is changed. A bare `++` switches back to the default toolchain.
   * `< <filename>`
     This runs commands from the file named by `<filename>`. It looks for a command on each line, and
-    lines that are empty or that start with `#` are ignored.
+    lines that are empty or that start with `#` are ignored. The script stops at the first command
+    that fails, unless it contains a `set +e` line.
+  * `<command> ; <command>`, `<command> && <command>`, `<command> || <command>`
+    Runs several commands, one after the other. The command after `&&` only runs if the one
+    before it succeeded, and the one after `||` only if it failed. Any of them can be a special
+    command, like `++nightly test && +bench`.
+  * `do <command>, <command>, ...`
+    Runs the commands one after the other, stopping at the first one that fails.
+  * `<command> &`
+    Runs a cargo command in the background, with its output saved for later. `jobs` lists the
+    background jobs, `fg [<job>]` shows a job's output and waits for it to finish, and
+    `kill <job>` stops it.
+  * `toolchain`
+    Shows the active toolchain, and where the default toolchain setting came from.
+  * `toolchain list`
+    Lists the toolchains installed with rustup.
+  * `toolchain install <toolchain>`
+    Installs a toolchain with rustup.
+  * `toolchain add-component <component>...` and `toolchain add-target <target>...`
+    Adds components or targets to the active toolchain.
+  * `toolchain pin [<toolchain>]`
+    Writes a `rust-toolchain.toml` file that pins the project to a toolchain, the active one if
+    `<toolchain>` is left off.
+  * `msrv [--bisect]`
+    Checks that the crate builds with the Rust version in its `rust-version`, by running
+    `cargo-shell.msrv-command` (`check` by default) on that toolchain. With `--bisect`, it looks for
+    the oldest installed toolchain that builds the crate instead.
+  * `cd [<dir>]`, `pwd`
+    Changes the shell's working directory, or shows it. The project and toolchain are picked up
+    from the new directory. `cd` on its own goes to the root of the workspace, and `cd -` goes
+    back to the previous directory.
+  * `pushd [<dir>]`, `popd`, `dirs`
+    Changes directory like `cd`, but saves the old one so that `popd` can go back to it. `dirs`
+    lists the saved directories.
+  * `members`
+    Lists the packages in the workspace.
+  * `use [<member>]`
+    Runs the following commands for just one package in the workspace, by adding `-p <member>` to
+    commands that accept it. `use` on its own goes back to running commands for the whole workspace.
+  * `reload`
+    Reads the project's manifests and the `cargo-shell` settings again. This happens automatically
+    when `Cargo.toml` or `.cargo/config` is changed.
+  * `alias [<name>[=<command>]]`
+    Defines an alias for the rest of the session, so that `<name>` runs `<command>` instead. In the
+    command, `$1` to `$9` stand for the arguments given to the alias and `$@` for all of them.
+    `alias <name>` shows an alias, and `alias` on its own lists all of them, including the ones
+    from the `cargo-shell.aliases` setting. `unalias <name>` removes an alias.
+  * `set <name>=<value>`, `unset <name>` and `env`
+    Sets or removes an environment variable for every command run from now on, and lists the
+    variables that have been changed. `<name>=<value> <command>` sets a variable for just that one
+    command. Variables for a single toolchain can be set in `[cargo-shell.env.<toolchain>]`.
+  * `history`
+    Lists the commands run in this project so far. They can be re-run with `!!` (the previous
+    command), `!<n>` (command number `<n>`), `!-<n>` (the `<n>`th previous command), `!<prefix>`
+    (the most recent command that starts with `<prefix>`) or `!?<str>` (the most recent command
+    that contains `<str>`).
+  * `! <command>`
+    Runs `<command>` with your system shell (`$SHELL`), in the shell's directory and with its
+    variables. The space after the `!` is needed, since `!<prefix>` re-runs a command from the
+    history. Any `rustc`, `rustdoc` or `cargo` it runs uses the active toolchain, so
+    `! rustc --version` shows the version of the toolchain commands are running on.
   * `~ <command>`
-    This command is only available if `cargo-watch` is available. It will run the `<command>` using
-    `cargo-watch`, which causes the command to be re-run whenever a source file changes.
+    This runs the `<command>`, and runs it again whenever a source file changes, until Ctrl-C is
+    pressed. The `<command>` can be a special command too, like `~+test`.
+  * `exit [<status>]`, `quit [<status>]`
+    Leaves the shell, stopping any background jobs. The exit status is `<status>` if it is given,
+    or else the exit status of the last command.
 
 "#;
 
-// TODO: this should come from rustup instead of being specified here
-const DEFAULT_TOOLCHAIN: &'static str = "stable";
+/// Subcommands that accept `-p <package>`
+const PACKAGE_SUBCOMMANDS: &'static [&'static str] = &[
+    "bench", "build", "check", "clean", "doc", "run", "rustc", "rustdoc", "test", "update",
+];
+
+/// Subcommands that only make sense inside of a project
+const PROJECT_SUBCOMMANDS: &'static [&'static str] = &[
+    "bench", "build", "check", "clean", "doc", "fetch", "generate-lockfile", "locate-project",
+    "metadata", "package", "pkgid", "publish", "read-manifest", "run", "rustc", "rustdoc", "test",
+    "update", "verify-project",
+];
+
+/// Flags to `cargo new` and `cargo init` that take a value
+const NEW_VALUE_FLAGS: &'static [&'static str] = &["--color", "--name", "--vcs"];
+
+/// A package in the workspace.
+struct Member {
+    pub name: String,
+    pub version: String,
+    pub root: PathBuf,
+}
 
 struct Config {
-    pub prompt: String,
+    pub prompt: Template,
     pub rustup: PathBuf,
     pub name: String,
     pub version: String,
+    /// The manifest of the project the shell was started in, if it was started in one
+    pub manifest: Option<PathBuf>,
+    /// The root directory of the workspace
+    pub root: PathBuf,
+    pub members: Vec<Member>,
+    /// The member selected with `use`, which commands are run for
+    pub package: Option<String>,
     pub default_toolchain: String,
+    pub toolchain_source: toolchain::Source,
     pub toolchains: Vec<String>,
+    /// Whether `~` clears the screen before re-running its command
+    pub watch_clear: bool,
+    /// Whether `+` runs the toolchains at the same time, like `+&`
+    pub parallel_toolchains: bool,
+    /// The cargo command that `msrv` runs
+    pub msrv_command: String,
     pub current_toolchain: String,
     pub cwd: PathBuf,
+    /// The directory before the last `cd`, for `cd -`
+    pub previous_dir: Option<PathBuf>,
+    /// The directories saved with `pushd`, most recent last
+    pub dir_stack: Vec<PathBuf>,
+    /// The scripts currently being run with `<`, innermost last
+    pub scripts: Vec<PathBuf>,
+    pub history: History,
+    pub completions: Rc<RefCell<Completions>>,
+    pub git: Option<GitStatus>,
+    /// How the last command run from the prompt finished
+    pub last_outcome: Option<Outcome>,
+    pub jobs: Jobs,
+    pub aliases: Aliases,
+    /// Variables set with `set`, or removed with `unset` when they are `None`
+    pub env: BTreeMap<String, Option<String>>,
+    /// Variables for particular toolchains, from `[cargo-shell.env.<toolchain>]`
+    pub toolchain_env: BTreeMap<String, BTreeMap<String, String>>,
+    /// Variables given in front of the command being run, like `NAME=value build`
+    pub command_env: BTreeMap<String, String>,
+    /// The aliases being expanded, which aren't expanded again until they are done
+    pub alias_stack: Vec<String>,
+    /// Whether the shell reads its own commands from standard input, which nothing it runs may read
+    pub commands_from_stdin: bool,
+    /// Modification times of the manifests, to notice when they are edited
+    pub manifest_snapshot: Snapshot,
+    /// Modification times of the cargo configuration files
+    pub config_snapshot: Snapshot,
 }
 
 impl Config {
-    fn prompt(cconfig: &CargoConfig) -> Result<String> {
+    fn prompt(cconfig: &CargoConfig) -> Result<Template> {
         let prompt= cconfig.get_string("cargo-shell.prompt").chain_err(|| "Could not find cargo-shell.prompt")?;
         let prompt = match prompt {
             Some(prompt) => prompt.val,
             None => ">> ".to_string()
         };
 
-        Ok(prompt)
+        Template::parse(&prompt).chain_err(|| "Invalid cargo-shell.prompt")
     }
 
     fn get_prompt(&self) -> String {
-        let prompt = self.prompt.replace("{project}", &self.name)
-                                .replace("{version}", &self.version)
-                                .replace("{toolchain}", &self.current_toolchain);
+        self.prompt.render(&self.prompt_vars())
+    }
+
+    /// The values of the variables that can be used in the prompt.
+    fn prompt_vars(&self) -> HashMap<&'static str, String> {
+        let mut vars = HashMap::new();
+        vars.insert("project", self.name.clone());
+        vars.insert("version", self.version.clone());
+        vars.insert("toolchain", self.current_toolchain.clone());
+        vars.insert("package", self.package.clone().unwrap_or_else(|| self.name.clone()));
+
+        vars.insert("cwd", tilde(&self.cwd));
 
-        prompt
+        let last = self.last_outcome.unwrap_or_else(Outcome::ok);
+        vars.insert("status", last.status().to_string());
+        vars.insert("duration", match self.last_outcome {
+            Some(ref outcome) => outcome::format_duration(outcome.duration),
+            None => String::new(),
+        });
+
+        let git = self.git.clone().unwrap_or_default();
+        let count = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_default();
+        vars.insert("branch", git.branch.clone());
+        vars.insert("commit", git.commit.clone());
+        vars.insert("dirty", if git.dirty { "*".into() } else { String::new() });
+        vars.insert("ahead", count(git.ahead));
+        vars.insert("behind", count(git.behind));
+
+        vars
+    }
+
+    /// Re-reads the git status shown in the prompt. This only happens between commands, so
+    /// drawing the prompt stays fast.
+    fn refresh_git(&mut self) {
+        self.git = GitStatus::read(&self.cwd);
     }
 
-    fn default_toolchain(cconfig: &CargoConfig) -> Result<String> {
+    fn default_toolchain(cconfig: &CargoConfig) -> Result<(String, toolchain::Source)> {
         let def = cconfig.get_string("cargo-shell.default-toolchain").chain_err(|| "Could not find cargo-shell.default-toolchain")?;
-        let def = match def {
-            Some(d) => d.val,
-            None => DEFAULT_TOOLCHAIN.into(),
+        match def {
+            Some(d) => Ok((d.val, toolchain::Source::Config)),
+            None => toolchain::resolve(cconfig.cwd()).chain_err(|| "Could not determine the default toolchain"),
+        }
+    }
+
+    /// Loads the workspace around the current directory, returning the name and version of the
+    /// current package, the workspace root, and the workspace members.
+    fn get_workspace(cconfig: &CargoConfig, manifest: Option<&Path>) -> Result<(String, String, PathBuf, Vec<Member>)> {
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => return Ok((String::new(), String::new(), cconfig.cwd().into(), Vec::new())),
+        };
+        let ws = Workspace::new(manifest, cconfig).chain_err(|| "Could not load workspace for current crate")?;
+        let root = ws.root().to_path_buf();
+
+        let (name, version) = match ws.current() {
+            Ok(pkg) => (pkg.name().into(), pkg.version().to_string()),
+            // a virtual manifest doesn't have a package of its own, so name it after its directory
+            Err(_) => {
+                let name = root.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                (name, String::new())
+            },
+        };
+
+        let mut members = ws.members().map(|pkg| {
+            Member {
+                name: pkg.name().into(),
+                version: pkg.version().to_string(),
+                root: pkg.root().to_path_buf(),
+            }
+        }).collect::<Vec<_>>();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok((name, version, root, members))
+    }
+
+    /// Lists the workspace members, marking the one selected with `use`.
+    fn print_members(&self) {
+        for member in &self.members {
+            let selected = self.package.as_ref() == Some(&member.name);
+            let path = member.root.strip_prefix(&self.root).unwrap_or(&member.root);
+            println!("{} {} {} ({})",
+                     if selected { "*" } else { " " },
+                     member.name,
+                     member.version,
+                     if path.as_os_str().is_empty() { Path::new(".") } else { path }.display());
+        }
+    }
+
+    /// After `new` or `init` creates a crate, offers to switch the shell over to it.
+    fn offer_new_crate(&mut self, args: &[String]) -> Result<()> {
+        let subcommand = match args.first() {
+            Some(subcommand) if subcommand == "new" || subcommand == "init" => subcommand,
+            _ => return Ok(()),
+        };
+
+        // the path is the first argument that isn't a flag or the value of one
+        let mut path = None;
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            if arg.starts_with("-") {
+                if NEW_VALUE_FLAGS.contains(&&arg[..]) {
+                    rest.next();
+                }
+            } else {
+                path = Some(arg);
+                break;
+            }
+        }
+        let dir = match path {
+            Some(path) => self.cwd.join(path),
+            None if subcommand == "init" => self.cwd.clone(),
+            None => return Ok(()),
+        };
+
+        if !dir.join("Cargo.toml").is_file() || self.manifest.as_ref().map(|m| m.parent() == Some(&dir)).unwrap_or(false) {
+            return Ok(());
+        }
+        if confirm(&format!("Enter the new crate at {}?", dir.display())) {
+            self.enter(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the shell into `dir`, reloading everything about the project there.
+    fn enter(&mut self, dir: &Path) -> Result<()> {
+        self.history.save()?;
+        env::set_current_dir(dir).chain_err(|| format!("Could not change directory to {}", dir.display()))?;
+        let mut config = Config::new()?;
+        config.last_outcome = self.last_outcome;
+        // jobs keep running in the project they were started in
+        mem::swap(&mut config.jobs, &mut self.jobs);
+        // and so do the aliases defined in the session
+        config.aliases.take_session(&mut self.aliases);
+        mem::swap(&mut config.env, &mut self.env);
+        mem::swap(&mut config.dir_stack, &mut self.dir_stack);
+        config.previous_dir = Some(self.cwd.clone());
+        config.commands_from_stdin = self.commands_from_stdin;
+        config.completions.borrow_mut().aliases = config.aliases.names();
+        *self = config;
+        Ok(())
+    }
+
+    /// Moves the shell to `dir`, and picks up the project there. Within a workspace only the
+    /// manifests and settings need reloading, but another workspace gets its own history too.
+    fn change_dir(&mut self, dir: &Path) -> Result<()> {
+        let dir = self.cwd.join(dir);
+        let dir = dir.canonicalize().chain_err(|| format!("Could not change directory to {}", dir.display()))?;
+        if !dir.is_dir() {
+            bail!("Not a directory: {}", dir.display());
+        }
+        env::set_current_dir(&dir).chain_err(|| format!("Could not change directory to {}", dir.display()))?;
+        self.previous_dir = Some(mem::replace(&mut self.cwd, dir));
+
+        let root = self.root.clone();
+        let had_project = self.manifest.is_some();
+        self.reload(true)?;
+        if self.root != root {
+            if had_project {
+                println!("warning: leaving the workspace at {}", root.display());
+            }
+            self.package = None;
+            self.history.save()?;
+            let cconfig = CargoConfig::default().chain_err(|| "Could not get default CargoConfig")?;
+            self.history = Config::history(&cconfig, &self.root)?;
+        }
+        self.refresh_git();
+        Ok(())
+    }
+
+    /// Prints the working directory followed by the directories saved with `pushd`.
+    fn print_dirs(&self) {
+        let dirs = Some(&self.cwd).into_iter()
+                                  .chain(self.dir_stack.iter().rev())
+                                  .map(|d| tilde(d))
+                                  .collect::<Vec<_>>();
+        println!("{}", dirs.join(" "));
+    }
+
+    /// Adds `-p <package>` to commands that take it, when a member has been selected with `use`
+    /// and the command doesn't already choose its packages.
+    fn package_args(&self, cmd: &[String]) -> Vec<String> {
+        let mut args = cmd.to_vec();
+        if let Some(ref package) = self.package {
+            let takes_package = cmd.first().map(|c| PACKAGE_SUBCOMMANDS.contains(&&c[..])).unwrap_or(false);
+            let chooses_package = cmd.iter().any(|a| {
+                a == "-p" || a.starts_with("--package") || a == "--all" || a == "--workspace" || a == "--"
+            });
+            if takes_package && !chooses_package {
+                args.insert(1, "-p".into());
+                args.insert(2, package.clone());
+            }
+        }
+        args
+    }
+
+    fn history(cconfig: &CargoConfig, root: &Path) -> Result<History> {
+        let size = cconfig.get_i64("cargo-shell.history-size").chain_err(|| "Could not get cargo-shell.history-size value")?;
+        let size = match size {
+            Some(ref size) if size.val >= 0 => size.val as usize,
+            Some(_) => bail!("cargo-shell.history-size cannot be negative"),
+            None => DEFAULT_HISTORY_SIZE,
+        };
+
+        let file = cconfig.get_path("cargo-shell.history-file").chain_err(|| "Could not get cargo-shell.history-file value")?;
+        let file = match file {
+            Some(file) => file.val,
+            None => {
+                // every workspace gets its own history, named after the root directory so that
+                // the files are easy to tell apart. Outside of a project, the root is the directory
+                // the shell was started in. The hash has to stay the same between Rust releases,
+                // which `DefaultHasher` doesn't promise.
+                let name = root.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                cconfig.home()
+                       .join("cargo-shell")
+                       .join("history")
+                       .join(format!("{}-{:016x}", name, fnv1a(root.as_os_str().as_bytes())))
+                       .into_path_unlocked()
+            },
         };
-        Ok(def)
+        debug!("history file is {:?}", file);
+
+        History::load(file, size)
+    }
+
+    fn completions(cconfig: &CargoConfig, manifest: Option<&Path>, rustup: &Path, toolchains: &[String]) -> Result<Completions> {
+        Completions::load(cconfig, manifest, rustup, toolchains).chain_err(|| "Could not load tab completions")
     }
 
-    fn get_name_and_version(cconfig: &CargoConfig) -> Result<(String, String)> {
-        let manifest = find_root_manifest_for_wd(None, cconfig.cwd()).chain_err(|| "Could not find root manifest for project")?;
-        let pkg = Package::for_path(&manifest, cconfig).chain_err(|| "Could not get package path for current crate")?;
-        Ok((pkg.name().into(), pkg.version().to_string()))
+    fn watch_clear(cconfig: &CargoConfig) -> Result<bool> {
+        let clear = cconfig.get_bool("cargo-shell.watch-clear").chain_err(|| "Could not get cargo-shell.watch-clear value")?;
+        Ok(clear.map(|c| c.val).unwrap_or(false))
     }
 
-    fn get_toolchains(cconfig: &CargoConfig) -> Result<Vec<String>> {
+    fn msrv_command(cconfig: &CargoConfig) -> Result<String> {
+        let command = cconfig.get_string("cargo-shell.msrv-command").chain_err(|| "Could not get cargo-shell.msrv-command value")?;
+        Ok(command.map(|c| c.val).unwrap_or_else(|| "check".into()))
+    }
+
+    fn toolchain_env(cconfig: &CargoConfig) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+        let table = cconfig.get_table("cargo-shell.env").chain_err(|| "Could not get cargo-shell.env value")?;
+        let mut toolchain_env = BTreeMap::new();
+        for (toolchain, vars) in table.map(|t| t.val).unwrap_or_default() {
+            let vars = match vars {
+                ConfigValue::Table(vars, _) => vars,
+                _ => bail!("cargo-shell.env.{} must be a table of variables", toolchain),
+            };
+            let mut env = BTreeMap::new();
+            for (name, value) in vars {
+                match value {
+                    ConfigValue::String(value, _) => env.insert(name, value),
+                    _ => bail!("cargo-shell.env.{}.{} must be a string", toolchain, name),
+                };
+            }
+            toolchain_env.insert(toolchain, env);
+        }
+        Ok(toolchain_env)
+    }
+
+    /// The value of a variable, as set in the shell or inherited from its environment.
+    fn var(&self, name: &str) -> Option<String> {
+        match self.env.get(name) {
+            Some(value) => value.clone(),
+            None => env::var(name).ok(),
+        }
+    }
+
+    /// The value a variable has for a command run now, with the variables `apply_env` sets.
+    fn command_var(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.command_env.get(name) {
+            return Some(value.clone());
+        }
+        let mut value = None;
+        for (toolchain, vars) in &self.toolchain_env {
+            if self.is_active(toolchain) && vars.contains_key(name) {
+                value = vars.get(name).cloned();
+            }
+        }
+        value.or_else(|| self.var(name))
+    }
+
+    /// Whether `toolchain`, as named in `[cargo-shell.env.<toolchain>]`, is the active toolchain.
+    fn is_active(&self, toolchain: &str) -> bool {
+        toolchain::matches(&self.current_toolchain, toolchain) || toolchain == self.current_toolchain
+    }
+
+    /// Sets the shell's variables on a command that is about to be run. Variables for the active
+    /// toolchain override the ones set with `set`, and the ones given in front of the command
+    /// override both.
+    fn apply_env(&self, command: &mut Command) {
+        for (name, value) in &self.env {
+            match *value {
+                Some(ref value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+        for (toolchain, vars) in &self.toolchain_env {
+            if self.is_active(toolchain) {
+                command.envs(vars);
+            }
+        }
+        command.envs(&self.command_env);
+    }
+
+    /// Lists the variables changed in the shell, and the ones for the active toolchain.
+    fn print_env(&self) {
+        for (name, value) in &self.env {
+            match *value {
+                Some(ref value) => println!("{}={}", name, lexer::quote(value)),
+                None => println!("unset {}", name),
+            }
+        }
+        for (toolchain, vars) in &self.toolchain_env {
+            if self.is_active(toolchain) {
+                for (name, value) in vars {
+                    println!("{}={}  ({})", name, lexer::quote(value), toolchain);
+                }
+            }
+        }
+    }
+
+    fn parallel_toolchains(cconfig: &CargoConfig) -> Result<bool> {
+        let parallel = cconfig.get_bool("cargo-shell.parallel-toolchains").chain_err(|| "Could not get cargo-shell.parallel-toolchains value")?;
+        Ok(parallel.map(|p| p.val).unwrap_or(false))
+    }
+
+    /// The toolchains for `+`, leaving out any that aren't installed. Only the ones that were
+    /// configured explicitly are warned about.
+    fn get_toolchains(cconfig: &CargoConfig, rustup: &Path, manifest: Option<&Path>, root: &Path) -> Result<Vec<String>> {
         let toolchains = cconfig.get_list("cargo-shell.toolchains").chain_err(|| "Could not get cargo-shell.toolchains value")?;
-        let toolchains = match toolchains {
-            Some(toolchains) => toolchains.val.into_iter().map(|(s, _p)| s).collect::<Vec<_>>(),
-            None => vec!["stable".into(), "beta".into(), "nightly".into()],
+        let (entries, configured) = match toolchains {
+            Some(toolchains) => (toolchains.val.into_iter().map(|(s, _p)| s).collect::<Vec<_>>(), true),
+            None => (vec!["stable".into(), "beta".into(), "nightly".into()], false),
+        };
+
+        let installed = match toolchain::installed(rustup) {
+            Ok(installed) => installed,
+            Err(e) => {
+                debug!("could not list installed toolchains: {}", e);
+                return Ok(entries);
+            },
+        };
+        let rust_version = match manifest {
+            Some(manifest) => toolchain::rust_version(manifest, &root.join("Cargo.toml"))?,
+            None => None,
         };
+        let (toolchains, warnings) = toolchain::validate(&entries, &installed, rust_version.as_ref().map(|v| &v[..]));
+        for warning in warnings {
+            if configured {
+                println!("warning: cargo-shell.toolchains: {}", warning);
+            } else {
+                debug!("{}", warning);
+            }
+        }
         Ok(toolchains)
     }
 
@@ -120,42 +606,218 @@ impl Config {
 
     fn new() -> Result<Config> {
         let cconfig = CargoConfig::default().chain_err(|| "Could not get default CargoConfig")?;
-        let (name, version) = Config::get_name_and_version(&cconfig)?;
+        // not finding a project is fine, since `new`, `init` and `install` work without one
+        let manifest = find_root_manifest_for_wd(None, cconfig.cwd()).ok();
+        debug!("project manifest is {:?}", manifest);
+        let (name, version, root, members) = Config::get_workspace(&cconfig, manifest.as_ref().map(|m| &**m))?;
         let prompt = Config::prompt(&cconfig)?;
 
         let rustup = Config::find_rustup().chain_err(|| "Could not find a `rustup` binary")?;
         debug!("rustup binary found at {:?}", rustup.to_string_lossy());
 
-        let default_toolchain = Config::default_toolchain(&cconfig)?;
+        let (default_toolchain, toolchain_source) = Config::default_toolchain(&cconfig)?;
+        debug!("default toolchain is {} (from {})", default_toolchain, toolchain_source);
+
+        let toolchains = Config::get_toolchains(&cconfig, &rustup, manifest.as_ref().map(|m| &**m), &root)?;
+        let watch_clear = Config::watch_clear(&cconfig)?;
+        let parallel_toolchains = Config::parallel_toolchains(&cconfig)?;
+        let msrv_command = Config::msrv_command(&cconfig)?;
+        let toolchain_env = Config::toolchain_env(&cconfig)?;
+
+        let history = Config::history(&cconfig, &root)?;
 
-        let toolchains = Config::get_toolchains(&cconfig)?;
+        let aliases = Aliases::load(&cconfig)?;
+        let mut completions = Config::completions(&cconfig, manifest.as_ref().map(|m| &**m), &rustup, &toolchains)?;
+        completions.aliases = aliases.names();
 
-        Ok(Config {
+        let mut config = Config {
             prompt: prompt,
             rustup: rustup.into(),
             name: name,
             version: version,
+            manifest: manifest,
+            root: root,
+            members: members,
+            package: None,
             default_toolchain: default_toolchain.clone(),
+            toolchain_source: toolchain_source,
             toolchains: toolchains,
+            watch_clear: watch_clear,
+            parallel_toolchains: parallel_toolchains,
+            msrv_command: msrv_command,
             current_toolchain: default_toolchain.clone(),
             cwd: cconfig.cwd().into(),
-        })
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            scripts: Vec::new(),
+            history: history,
+            completions: Rc::new(RefCell::new(completions)),
+            git: GitStatus::read(cconfig.cwd()),
+            last_outcome: None,
+            jobs: Jobs::default(),
+            aliases: aliases,
+            env: BTreeMap::new(),
+            toolchain_env: toolchain_env,
+            command_env: BTreeMap::new(),
+            alias_stack: Vec::new(),
+            commands_from_stdin: false,
+            manifest_snapshot: Snapshot::default(),
+            config_snapshot: Snapshot::default(),
+        };
+        config.snapshot();
+        Ok(config)
+    }
+
+    /// Re-reads the project's manifests, and the `cargo-shell.*` settings too if `settings` is set.
+    fn reload(&mut self, settings: bool) -> Result<()> {
+        let cconfig = CargoConfig::default().chain_err(|| "Could not get default CargoConfig")?;
+        let manifest = find_root_manifest_for_wd(None, cconfig.cwd()).ok();
+        let (name, version, root, members) = Config::get_workspace(&cconfig, manifest.as_ref().map(|m| &**m))?;
+
+        if settings {
+            self.prompt = Config::prompt(&cconfig)?;
+            let (default_toolchain, toolchain_source) = Config::default_toolchain(&cconfig)?;
+            // only follow the new default if the toolchain wasn't changed with `++`
+            if self.current_toolchain == self.default_toolchain {
+                self.current_toolchain = default_toolchain.clone();
+            }
+            self.default_toolchain = default_toolchain;
+            self.toolchain_source = toolchain_source;
+            self.toolchains = Config::get_toolchains(&cconfig, &self.rustup, manifest.as_ref().map(|m| &**m), &root)?;
+            self.watch_clear = Config::watch_clear(&cconfig)?;
+            self.parallel_toolchains = Config::parallel_toolchains(&cconfig)?;
+            self.msrv_command = Config::msrv_command(&cconfig)?;
+            self.aliases.reload(&cconfig)?;
+            self.toolchain_env = Config::toolchain_env(&cconfig)?;
+        }
+
+        let mut completions = Config::completions(&cconfig, manifest.as_ref().map(|m| &**m), &self.rustup, &self.toolchains)?;
+        completions.aliases = self.aliases.names();
+        *self.completions.borrow_mut() = completions;
+
+        if self.package.as_ref().map(|p| !members.iter().any(|m| &m.name == p)).unwrap_or(false) {
+            println!("`{}` is no longer a member of the workspace", self.package.take().unwrap_or_default());
+        }
+        self.name = name;
+        self.version = version;
+        self.manifest = manifest;
+        self.root = root;
+        self.members = members;
+        self.snapshot();
+        Ok(())
+    }
+
+    /// Reloads whatever has changed on disk since it was last read.
+    fn reload_if_changed(&mut self) -> Result<()> {
+        if self.config_snapshot.changed() {
+            debug!("cargo configuration changed, reloading settings");
+            self.reload(true)
+        } else if self.manifest_snapshot.changed() {
+            debug!("manifest changed, reloading project");
+            self.reload(false)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn snapshot(&mut self) {
+        self.manifest_snapshot = Snapshot::take(self.manifest_files());
+        self.config_snapshot = Snapshot::take(self.config_files());
+    }
+
+    /// The manifests of the project and all of its workspace members.
+    fn manifest_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.root.join("Cargo.toml")];
+        files.extend(self.manifest.iter().cloned());
+        files.extend(self.members.iter().map(|m| m.root.join("Cargo.toml")));
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// The cargo configuration files that apply in the shell's directory.
+    fn config_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dir = Some(&*self.cwd);
+        while let Some(d) = dir {
+            files.push(d.join(".cargo").join("config"));
+            dir = d.parent();
+        }
+        let cargo_home = match env::var_os("CARGO_HOME") {
+            Some(home) => Some(PathBuf::from(home)),
+            None => env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")),
+        };
+        if let Some(home) = cargo_home {
+            files.push(home.join("config"));
+        }
+        files
     }
 }
 
 pub fn main() -> Result<()> {
     let v = env!("CARGO_PKG_VERSION");
     println!("Welcome to cargo-shell v{}", v);
-    let mut rl = Editor::<()>::new();
     let mut config = Config::new()?;
+    if config.manifest.is_none() {
+        println!("There is no cargo project here, but you can create one with `new` or `init`.");
+    }
+    let mut rl = Editor::<ShellCompleter>::new();
+    rl.set_completer(Some(ShellCompleter::new(config.completions.clone())));
+    for entry in config.history.entries() {
+        rl.add_history_entry(entry);
+    }
 
     loop {
+        config.jobs.notify();
+        if let Err(e) = config.reload_if_changed() {
+            println!("Error: {:?}", e);
+        }
         let line = rl.readline(&config.get_prompt());
         match line {
-            Ok(line) => {
-                if let Err(e) = dispatch_cmd(&mut config, &line.trim()) {
-                    println!("Error: {:?}", e);
+            Ok(mut line) => {
+                // keep reading while the line ends inside a quote or with a `\`
+                while lexer::is_incomplete(&line) {
+                    match rl.readline(".. ") {
+                        Ok(more) => {
+                            line.push('\n');
+                            line.push_str(&more);
+                        },
+                        Err(_) => break,
+                    }
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                config.history.add(line);
+                let root = config.root.clone();
+                config.last_outcome = match dispatch_cmd(&mut config, line) {
+                    Ok(outcome) => {
+                        if !outcome.success() {
+                            println!("Command {}", outcome);
+                        }
+                        Some(outcome)
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        Some(Outcome::failed())
+                    },
                 };
+                if config.root != root {
+                    // the shell moved to another project, which has its own history and completions
+                    rl.clear_history();
+                    for entry in config.history.entries() {
+                        rl.add_history_entry(entry);
+                    }
+                    rl.set_completer(Some(ShellCompleter::new(config.completions.clone())));
+                } else if let Some(entry) = config.history.entries().last() {
+                    // `!` commands replace themselves in the history with what they expanded to
+                    rl.add_history_entry(entry);
+                }
+                if let Err(e) = config.history.save() {
+                    println!("Error: {:?}", e);
+                }
+                config.refresh_git();
             },
             Err(ReadlineError::Eof) => break,
             Err(ReadlineError::Interrupted) => continue,
@@ -163,104 +825,690 @@ pub fn main() -> Result<()> {
         }
     }
 
+    config.jobs.kill_all();
     Ok(())
 }
 
-fn dispatch_cmd(config: &mut Config, cmd: &str) -> Result<()> {
-    if cmd == "exit" || cmd == "quit" {
-        ::std::process::exit(0);
+/// Runs the commands in the script at `path` without starting an interactive session, returning
+/// the exit code of the script.
+pub fn script<P: AsRef<Path>>(path: P) -> Result<i32> {
+    let mut config = Config::new()?;
+    let outcome = script::run_file(&mut config, path.as_ref());
+    config.jobs.kill_all();
+    Ok(outcome?.status())
+}
+
+/// Runs a single command line without starting an interactive session, returning its exit status.
+pub fn command(line: &str) -> Result<i32> {
+    let mut config = Config::new()?;
+    let outcome = match dispatch_cmd(&mut config, line) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            Outcome::failed()
+        },
+    };
+    config.jobs.kill_all();
+    Ok(outcome.status())
+}
+
+/// Runs the commands read from standard input, the same way a script is run, and returns the exit
+/// status of the last one.
+pub fn stdin() -> Result<i32> {
+    let mut config = Config::new()?;
+    config.commands_from_stdin = true;
+    let stdin = io::stdin();
+    let outcome = script::run_reader(&mut config, stdin.lock(), "<stdin>");
+    config.jobs.kill_all();
+    Ok(outcome?.status())
+}
+
+/// Whether the shell is reading from a terminal, and so can ask questions and use readline.
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) != 0 }
+}
+
+/// Runs a command line, which can be a list of commands joined with `;`, `&&` and `||`.
+fn dispatch_cmd(config: &mut Config, line: &str) -> Result<Outcome> {
+    if line.starts_with("!") && !History::is_reference(line) {
+        // `! <command>`, where the system shell gets the whole line, `;`, `&&` and `|` included
+        return shell_cmd(config, line);
+    }
+    let commands = lexer::split_list(line)?;
+    match commands.len() {
+        0 => return Ok(Outcome::ok()),
+        1 => return dispatch_one(config, &commands[0].1),
+        _ => {},
+    }
+
+    let _guard = signals::catch_interrupts();
+    let mut last = Outcome::ok();
+    for (connector, command) in commands {
+        let wanted = match connector {
+            Connector::Always => true,
+            Connector::IfSuccess => last.success(),
+            Connector::IfFailure => !last.success(),
+        };
+        if !wanted {
+            continue;
+        }
+        last = match dispatch_one(config, &command) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                println!("Error: {:?}", e);
+                Outcome::failed()
+            },
+        };
+        // for an `exit` later in the list
+        config.last_outcome = Some(last);
+        // Ctrl-C stops the whole list, not just the command that was running
+        if signals::interrupted() {
+            break;
+        }
+    }
+    Ok(last)
+}
+
+fn dispatch_one(config: &mut Config, cmd: &str) -> Result<Outcome> {
+    let (vars, rest) = assignments(config, cmd)?;
+    if !vars.is_empty() {
+        if rest.is_empty() {
+            // on their own, the assignments work like `set`
+            for (name, value) in vars {
+                config.env.insert(name, Some(value));
+            }
+            return Ok(Outcome::ok());
+        }
+        // NAME=value <command>
+        // set variables for just this command
+        let mut command_env = config.command_env.clone();
+        command_env.extend(vars);
+        let saved = mem::replace(&mut config.command_env, command_env);
+        let outcome = dispatch_one(config, rest);
+        config.command_env = saved;
+        return outcome;
+    }
+
+    if cmd == "exit" || cmd == "quit" || cmd.starts_with("exit ") || cmd.starts_with("quit ") {
+        let status = exit_status(config, &cmd[4..])?;
+        config.jobs.kill_all();
+        ::std::process::exit(status);
+    } else if cmd.ends_with("&") && !cmd.ends_with("&&") && !cmd.ends_with("\\&") {
+        // <command> &
+        // run a command in the background
+        background(config, cmd[..cmd.len() - 1].trim())
+    } else if cmd == "jobs" {
+        config.jobs.list();
+        Ok(Outcome::ok())
+    } else if cmd == "fg" || cmd.starts_with("fg ") {
+        let id = job_id(&cmd[2..])?;
+        config.jobs.fg(id)
+    } else if cmd.starts_with("kill ") {
+        match job_id(&cmd[4..])? {
+            Some(id) => config.jobs.kill(id)?,
+            None => bail!("Usage: kill <job>"),
+        }
+        Ok(Outcome::ok())
     } else if cmd == "help" {
         print_help();
+        Ok(Outcome::ok())
+    } else if cmd == "toolchain" || cmd.starts_with("toolchain ") {
+        let args = words(config, &cmd["toolchain".len()..])?;
+        toolchain_cmd(config, &args)
+    } else if cmd == "msrv" || cmd.starts_with("msrv ") {
+        // msrv [--bisect]
+        // check the crate against its minimum supported rust version
+        match &words(config, &cmd[4..])?[..] {
+            [] => msrv::msrv(config, false),
+            [flag] if flag == "--bisect" => msrv::msrv(config, true),
+            _ => bail!("Usage: msrv [--bisect]"),
+        }
+    } else if cmd == "pwd" {
+        println!("{}", config.cwd.display());
+        Ok(Outcome::ok())
+    } else if cmd == "cd" || cmd.starts_with("cd ") {
+        // cd [<dir> | -]
+        // without a directory, go back to the root of the workspace
+        let args = words(config, &cmd[2..])?;
+        let dir = match args.len() {
+            0 => config.root.clone(),
+            1 if args[0] == "-" => match config.previous_dir.clone() {
+                Some(dir) => dir,
+                None => bail!("There is no previous directory"),
+            },
+            1 => home_path(&args[0]),
+            _ => bail!("Usage: cd [<dir>]"),
+        };
+        config.change_dir(&dir)?;
+        Ok(Outcome::ok())
+    } else if cmd == "pushd" || cmd.starts_with("pushd ") {
+        // pushd [<dir>]
+        // without a directory, swap the working directory with the last one saved
+        let args = words(config, &cmd["pushd".len()..])?;
+        let cwd = config.cwd.clone();
+        match args.len() {
+            0 => {
+                let dir = match config.dir_stack.pop() {
+                    Some(dir) => dir,
+                    None => bail!("There is no other directory"),
+                };
+                if let Err(e) = config.change_dir(&dir) {
+                    config.dir_stack.push(dir);
+                    return Err(e);
+                }
+            },
+            1 => config.change_dir(&home_path(&args[0]))?,
+            _ => bail!("Usage: pushd [<dir>]"),
+        }
+        config.dir_stack.push(cwd);
+        config.print_dirs();
+        Ok(Outcome::ok())
+    } else if cmd == "popd" {
+        let dir = match config.dir_stack.pop() {
+            Some(dir) => dir,
+            None => bail!("The directory stack is empty"),
+        };
+        if let Err(e) = config.change_dir(&dir) {
+            config.dir_stack.push(dir);
+            return Err(e);
+        }
+        config.print_dirs();
+        Ok(Outcome::ok())
+    } else if cmd == "dirs" {
+        config.print_dirs();
+        Ok(Outcome::ok())
+    } else if cmd == "members" {
+        config.print_members();
+        Ok(Outcome::ok())
+    } else if cmd == "use" || cmd.starts_with("use ") {
+        // use [<member>]
+        // run commands for a single workspace member, or for the whole workspace again
+        let args = words(config, &cmd[3..])?;
+        match args.len() {
+            0 => config.package = None,
+            1 => {
+                if !config.members.iter().any(|m| m.name == args[0]) {
+                    bail!("`{}` is not a member of this workspace, see `members`", args[0]);
+                }
+                config.package = Some(args[0].clone());
+            },
+            _ => bail!("Usage: use [<member>]"),
+        }
+        Ok(Outcome::ok())
+    } else if cmd == "reload" {
+        config.reload(true)?;
+        Ok(Outcome::ok())
+    } else if cmd == "alias" || cmd.starts_with("alias ") {
+        alias_cmd(config, cmd["alias".len()..].trim())
+    } else if cmd.starts_with("unalias ") {
+        for name in words(config, &cmd["unalias".len()..])? {
+            config.aliases.remove(&name)?;
+        }
+        config.completions.borrow_mut().aliases = config.aliases.names();
+        Ok(Outcome::ok())
+    } else if cmd == "env" {
+        config.print_env();
+        Ok(Outcome::ok())
+    } else if cmd.starts_with("set ") {
+        // set <name>=<value>...
+        let args = words(config, &cmd["set".len()..])?;
+        if args == ["-e"] || args == ["+e"] {
+            // scripts handle these lines themselves, before they get here
+            bail!("`set -e` and `set +e` only work in scripts");
+        }
+        if args.is_empty() || !args.iter().all(|a| lexer::is_assignment(a)) {
+            bail!("Usage: set <name>=<value>");
+        }
+        for arg in args {
+            let i = arg.find('=').unwrap_or(0);
+            config.env.insert(arg[..i].into(), Some(arg[i + 1..].into()));
+        }
+        Ok(Outcome::ok())
+    } else if cmd.starts_with("unset ") {
+        for name in words(config, &cmd["unset".len()..])? {
+            config.env.insert(name, None);
+        }
+        Ok(Outcome::ok())
+    } else if cmd == "history" {
+        config.history.print();
+        Ok(Outcome::ok())
+    } else if cmd.starts_with("!") && History::is_reference(cmd) {
+        // !!, !n, !-n, !prefix, !?str
+        // re-run a command from the history
+        let expanded = config.history.expand(cmd)?;
+        println!("{}", expanded);
+        // only when it is the whole line, and not part of a list
+        if config.history.entries().last().map(|l| l == cmd).unwrap_or(false) {
+            config.history.replace_last(&expanded);
+        }
+        dispatch_cmd(config, &expanded)
+    } else if cmd.starts_with("!") {
+        shell_cmd(config, cmd)
+    } else if cmd == "do" || cmd.starts_with("do ") {
+        // do <command>, <command>, ...
+        // run commands one after the other, stopping at the first one that fails
+        let mut outcome = Outcome::ok();
+        for command in lexer::split_commas(&cmd[2..])? {
+            outcome = dispatch_cmd(config, &command)?;
+            if !outcome.success() {
+                break;
+            }
+        }
+        Ok(outcome)
     } else if cmd.starts_with("p ") {
-        let p = cmd[2..].trim_matches(|c| c == '"' || c == '\'' ).to_string();
-        config.prompt = p;
+        config.prompt = Template::parse(&words(config, &cmd[2..])?.join(" "))?;
+        Ok(Outcome::ok())
     } else if cmd.starts_with("~") {
         // ~command
         // run every time a source file changes
-        // only available if cargo-watch is installed
-        let has_cargo_watch = match Command::new("cargo")
-                                      .arg("watch")
-                                      .arg("--help")
-                                      .stdout(Stdio::null())
-                                      .stdin(Stdio::null())
-                                      .stderr(Stdio::null())
-                                      .status() {
-            Ok(status) => status.success(),
-            _ => false,
-        };
-        if !has_cargo_watch {
-            let stderr = stderr();
-            let _ = writeln!(stderr.lock(),
-                    "Could not find cargo-watch, you might need to install it?");
-        } else {
-            let mut new_cmd = vec!["watch"];
-            new_cmd.extend_from_slice(&cmd[1..].trim().split(' ').collect::<Vec<_>>());
-            run(config, &new_cmd)?;
-        }
+        let clear = config.watch_clear;
+        watch::watch(config, cmd[1..].trim(), clear)
     } else if cmd.starts_with("<") {
         // < filename
         // run commands from file `filename`
-        let file = &cmd[1..].trim();
-        let file = File::open(file).chain_err(|| format!("Could not open filename {}", file))?;
-        let file = BufReader::new(file);
-        for line in file.lines() {
-            let line = line.chain_err(|| "Could not get next line from file")?;
-            let line = line.trim();
-            if line == "" || line.starts_with("#") {
-                continue;
-            }
-            let line = line.split(' ').collect::<Vec<_>>();
-            println!("want to run {:?}?", line);
-            //run(config, &line)?;
+        let file = words(config, &cmd[1..])?;
+        if file.len() != 1 {
+            bail!("Usage: < <filename>");
         }
+        script::run_file(config, Path::new(&file[0]))
     } else if cmd.starts_with("++") {
         // ++ <version> <command>
         // temporarily change the version of rust used to run commands
-        let parts = cmd[2..].trim().split(' ').collect::<Vec<_>>();
-        let version = parts[0].trim();
+        let parts = words(config, &cmd[2..])?;
+        if parts.is_empty() {
+            // a bare `++` goes back to the default toolchain
+            config.current_toolchain = config.default_toolchain.clone();
+            return Ok(Outcome::ok());
+        }
+        if !ensure_toolchain(config, &parts[0])? {
+            return Ok(Outcome::failed());
+        }
         let original = config.current_toolchain.clone();
-        config.current_toolchain = version.into();
+        config.current_toolchain = parts[0].clone();
         // the command is actually optional, and will cause the toolchain switch to be temporary
         if parts.len() > 1 {
-            let _ = run(config, &parts[1..])?;
+            let outcome = run(config, &parts[1..]);
             config.current_toolchain = original;
+            outcome
+        } else {
+            Ok(Outcome::ok())
         }
+    } else if cmd.starts_with("+&") {
+        // +& <command>
+        // run the command across all toolchains at the same time
+        let mut args = words(config, &cmd[2..])?;
+        let fail_fast = multi::fail_fast(&mut args);
+        multi::run_parallel(config, &args, fail_fast)
     } else if cmd.starts_with("+") {
         // + <command>
         // run the command across all rust versions specified in the
         // `toolchains` setting list
-        let original = config.current_toolchain.clone();
-        let args = cmd[1..].trim().split(' ').collect::<Vec<_>>();
-        let toolchains = config.toolchains.clone();
-        for toolchain in toolchains {
-            config.current_toolchain = toolchain;
-            println!("Running command with toolchain `{}`", config.current_toolchain);
-            run(config, &args)?;
-        }
-        config.current_toolchain = original;
+        let mut args = words(config, &cmd[1..])?;
+        let fail_fast = multi::fail_fast(&mut args);
+        if config.parallel_toolchains {
+            multi::run_parallel(config, &args, fail_fast)
+        } else {
+            multi::run_sequential(config, &args, fail_fast)
+        }
     } else {
-        let args = cmd.split(' ').collect::<Vec<_>>();
-        run(config, &args)?;
+        let args = words(config, cmd)?;
+        if args.is_empty() {
+            return Ok(Outcome::ok());
+        }
+        if let Some(expanded) = expand_alias(config, &args) {
+            debug!("alias `{}` expands to `{}`", args[0], expanded);
+            config.alias_stack.push(args[0].clone());
+            let outcome = dispatch_cmd(config, &expanded);
+            config.alias_stack.pop();
+            return outcome;
+        }
+        let outcome = run(config, &args)?;
+        if outcome.success() {
+            config.offer_new_crate(&args)?;
+        }
+        Ok(outcome)
     }
-    Ok(())
+}
+
+/// Expands the alias that `args` starts with, unless it is already being expanded.
+fn expand_alias(config: &Config, args: &[String]) -> Option<String> {
+    if config.alias_stack.contains(&args[0]) {
+        return None;
+    }
+    config.aliases.get(&args[0]).map(|body| alias::substitute(body, &args[1..]))
+}
+
+/// The `alias` built-in, where `arg` is everything after `alias`.
+fn alias_cmd(config: &mut Config, arg: &str) -> Result<Outcome> {
+    if arg.is_empty() {
+        config.aliases.print();
+        return Ok(Outcome::ok());
+    }
+
+    let (name, body) = match arg.find('=') {
+        Some(i) => (arg[..i].trim(), arg[i + 1..].trim()),
+        None => {
+            match config.aliases.get(arg) {
+                Some(body) => println!("{} = {}", arg, body),
+                None => bail!("No such alias: {}", arg),
+            }
+            return Ok(Outcome::ok());
+        },
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        bail!("Invalid alias name: `{}`", name);
+    }
+    if complete::BUILTINS.contains(&name) {
+        bail!("`{}` is a built-in command, so it can't be an alias", name);
+    }
+    // `alias t='test --all'` quotes the whole command, but `alias t=test --all` works too. Only the
+    // quotes are taken off here: `$1` and other variables are left for when the alias is used.
+    let parts = lexer::split(body, |name| {
+        if name.chars().all(|c| c.is_digit(10)) {
+            Some(format!("${}", name))
+        } else {
+            Some(format!("${{{}}}", name))
+        }
+    })?;
+    let body = if parts.len() == 1 { parts[0].clone() } else { body.to_string() };
+    if body.trim().is_empty() {
+        bail!("Usage: alias <name>=<command>");
+    }
+    config.aliases.set(name, &body);
+    config.completions.borrow_mut().aliases = config.aliases.names();
+    Ok(Outcome::ok())
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Shortens a path in the home directory to start with `~`.
+fn tilde(path: &Path) -> String {
+    if let Some(home) = env::var_os("HOME") {
+        // compared a component at a time, so `/home/al` doesn't shorten `/home/alice`
+        match path.strip_prefix(&home) {
+            Ok(rest) if !home.is_empty() && rest.as_os_str().is_empty() => return "~".into(),
+            Ok(rest) if !home.is_empty() => return format!("~/{}", rest.display()),
+            _ => {},
+        }
+    }
+    path.display().to_string()
+}
+
+/// Expands a leading `~` in a path to the home directory.
+fn home_path(path: &str) -> PathBuf {
+    match env::var_os("HOME") {
+        Some(ref home) if path == "~" => home.into(),
+        Some(ref home) if path.starts_with("~/") => Path::new(home).join(&path[2..]),
+        _ => path.into(),
+    }
+}
+
+/// Asks a yes or no question on the terminal, where yes is the default. Without a terminal, the
+/// answer is no.
+fn confirm(question: &str) -> bool {
+    if !is_interactive() {
+        // standard input is where the commands are coming from, so there's nobody to answer
+        println!("{} [Y/n] n", question);
+        return false;
+    }
+    print!("{} [Y/n] ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => {
+            let answer = answer.trim().to_lowercase();
+            answer.is_empty() || answer == "y" || answer == "yes"
+        },
+        Err(_) => false,
+    }
+}
+
+/// Splits the arguments of a command into words, expanding any environment variables.
+fn words(config: &Config, args: &str) -> Result<Vec<String>> {
+    lexer::split(args, |name| config.var(name))
+}
+
+/// Splits the `NAME=value` assignments off the front of a command, returning them and the rest of
+/// the command.
+fn assignments<'a>(config: &Config, cmd: &'a str) -> Result<(Vec<(String, String)>, &'a str)> {
+    let mut vars = Vec::new();
+    let mut rest = cmd.trim();
+    loop {
+        let (word, after) = lexer::first_word(rest)?;
+        if !lexer::is_assignment(word) {
+            break;
+        }
+        let word = words(config, word)?.pop().unwrap_or_default();
+        let i = word.find('=').unwrap_or(0);
+        vars.push((word[..i].to_string(), word[i + 1..].to_string()));
+        rest = after.trim();
+    }
+    Ok((vars, rest))
 }
 
 fn print_help() {
     println!("{}", USAGE);
 }
 
-fn run(config: &Config, cmd: &[&str]) -> Result<()> {
+/// Builds the command that runs `cargo <cmd>` on the active toolchain, or returns `None` after
+/// warning about it if `cmd` can't be run here.
+fn cargo_command(config: &Config, cmd: &[String]) -> Option<Command> {
+    if config.manifest.is_none() && cmd.first().map(|c| PROJECT_SUBCOMMANDS.contains(&&c[..])).unwrap_or(false) {
+        println!("warning: `{}` needs a cargo project, but there isn't one here. Try `new` or `init` first.", cmd[0]);
+        return None;
+    }
+
+    let cmd = config.package_args(cmd);
     debug!("{} run {} cargo {}",
                 &config.rustup.to_string_lossy(),
-                &config.default_toolchain,
+                &config.current_toolchain,
                 cmd.join(" "));
-    let _ = Command::new(&config.rustup)
-                        .arg("run")
-                        .arg(&config.default_toolchain)
-                        .arg("cargo")
-                        .args(cmd)
-                        .current_dir(&config.cwd)
-                        .status()
-                        .chain_err(|| "Could not execute rustup run command")?;
-    Ok(())
+    let mut command = Command::new(&config.rustup);
+    command.arg("run")
+           .arg(&config.current_toolchain)
+           .arg("cargo")
+           .args(&cmd)
+           .current_dir(&config.cwd);
+    config.apply_env(&mut command);
+    Some(command)
+}
+
+fn run(config: &Config, cmd: &[String]) -> Result<Outcome> {
+    let mut command = match cargo_command(config, cmd) {
+        Some(command) => command,
+        None => return Ok(Outcome::failed()),
+    };
+    let outcome = foreground(config, &mut command)?;
+    debug!("`cargo {}` {}", cmd.join(" "), outcome);
+    Ok(outcome)
+}
+
+/// Runs `command` in the foreground. When the shell's commands come from standard input, the
+/// command gets none of it, so that it can't eat the commands after it.
+fn foreground(config: &Config, command: &mut Command) -> Result<Outcome> {
+    if config.commands_from_stdin {
+        command.stdin(Stdio::null());
+    }
+    process::run_foreground(command)
+}
+
+/// Runs `rustup <args>` in the shell's working directory.
+fn rustup(config: &Config, args: &[&str]) -> Result<Outcome> {
+    debug!("{} {}", config.rustup.to_string_lossy(), args.join(" "));
+    let mut command = Command::new(&config.rustup);
+    command.args(args)
+           .current_dir(&config.cwd);
+    config.apply_env(&mut command);
+    foreground(config, &mut command)
+}
+
+/// The `toolchain` built-in, which shows the toolchains in use and manages them with rustup.
+fn toolchain_cmd(config: &mut Config, args: &[String]) -> Result<Outcome> {
+    let args = args.iter().map(|a| &a[..]).collect::<Vec<_>>();
+    let outcome = match args.split_first() {
+        None => {
+            println!("active toolchain:  {}", config.current_toolchain);
+            println!("default toolchain: {} (from {})", config.default_toolchain, config.toolchain_source);
+            return Ok(Outcome::ok());
+        },
+        Some((&"list", &[])) => {
+            for name in toolchain::installed(&config.rustup)? {
+                let mut notes = Vec::new();
+                if toolchain::matches(&name, &config.current_toolchain) {
+                    notes.push("active");
+                }
+                if toolchain::matches(&name, &config.default_toolchain) {
+                    notes.push("default");
+                }
+                if notes.is_empty() {
+                    println!("{}", name);
+                } else {
+                    println!("{} ({})", name, notes.join(", "));
+                }
+            }
+            return Ok(Outcome::ok());
+        },
+        Some((&"install", &[name])) => rustup(config, &["toolchain", "install", name])?,
+        Some((&"add-component", components)) if !components.is_empty() => {
+            let mut rustup_args = vec!["component", "add", "--toolchain", &config.current_toolchain[..]];
+            rustup_args.extend(components);
+            rustup(config, &rustup_args)?
+        },
+        Some((&"add-target", targets)) if !targets.is_empty() => {
+            let mut rustup_args = vec!["target", "add", "--toolchain", &config.current_toolchain[..]];
+            rustup_args.extend(targets);
+            rustup(config, &rustup_args)?
+        },
+        Some((&"pin", rest)) if rest.len() <= 1 => {
+            let name = rest.first().cloned().unwrap_or(&config.current_toolchain).to_string();
+            let existing = config.root.join("rust-toolchain.toml");
+            if existing.is_file() && !confirm(&format!("Replace {}?", existing.display())) {
+                return Ok(Outcome::failed());
+            }
+            let file = toolchain::pin(&config.root, &name)?;
+            println!("Pinned {} to `{}` in {}", config.root.display(), name, file.display());
+            Outcome::ok()
+        },
+        _ => bail!("Usage: toolchain [list | install <toolchain> | add-component <component>... | \
+                    add-target <target>... | pin [<toolchain>]]"),
+    };
+    // installing things changes what can be completed, and pinning changes the default toolchain
+    config.reload(true)?;
+    Ok(outcome)
+}
+
+/// Checks that the toolchain `name` is installed before switching to it, and offers to install it
+/// if it isn't. Returns whether the toolchain can be used.
+fn ensure_toolchain(config: &mut Config, name: &str) -> Result<bool> {
+    let installed = match toolchain::installed(&config.rustup) {
+        Ok(installed) => installed,
+        Err(e) => {
+            // let `rustup run` be the judge, then
+            debug!("could not list installed toolchains: {}", e);
+            return Ok(true);
+        },
+    };
+    if toolchain::is_installed(&installed, name) {
+        return Ok(true);
+    }
+    if !confirm(&format!("The `{}` toolchain is not installed. Install it now?", name)) {
+        return Ok(false);
+    }
+    let outcome = rustup(config, &["toolchain", "install", name])?;
+    if outcome.success() {
+        config.reload(false)?;
+    }
+    Ok(outcome.success())
+}
+
+/// Runs a `! <command>` line with the system shell.
+fn shell_cmd(config: &Config, line: &str) -> Result<Outcome> {
+    let command = line[1..].trim();
+    if command.is_empty() {
+        bail!("Usage: ! <command>");
+    }
+    system(config, command)
+}
+
+/// Runs `line` with the system shell, in the shell's working directory and with its variables. The
+/// active toolchain is passed along to rustup, and rustup's proxies come first on the `PATH`, so
+/// that `rustc`, `rustdoc` and `cargo` all run on the active toolchain.
+fn system(config: &Config, line: &str) -> Result<Outcome> {
+    let shell = env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+    debug!("{:?} -c {}", shell, line);
+    let mut command = Command::new(shell);
+    command.arg("-c")
+           .arg(line)
+           .current_dir(&config.cwd);
+    config.apply_env(&mut command);
+
+    let mut paths = config.rustup.parent().into_iter().map(PathBuf::from).collect::<Vec<_>>();
+    if let Some(path) = config.command_var("PATH") {
+        paths.extend(env::split_paths(&path));
+    }
+    if let Ok(path) = env::join_paths(paths) {
+        command.env("PATH", path);
+    }
+    command.env("RUSTUP_TOOLCHAIN", &config.current_toolchain);
+    foreground(config, &mut command)
+}
+
+/// Starts a cargo command as a background job. Only plain cargo commands can be run in the
+/// background, optionally with a toolchain picked with `++`.
+fn background(config: &mut Config, cmd: &str) -> Result<Outcome> {
+    let (toolchain, args) = if cmd.starts_with("++") {
+        let mut parts = words(config, &cmd[2..])?;
+        if parts.len() < 2 {
+            bail!("Usage: ++ <toolchain> <command> &");
+        }
+        let toolchain = parts.remove(0);
+        (toolchain, parts)
+    } else if cmd.starts_with(|c| c == '+' || c == '~' || c == '<' || c == '!') {
+        bail!("Only cargo commands can be run in the background");
+    } else {
+        (config.current_toolchain.clone(), words(config, cmd)?)
+    };
+    if args.is_empty() {
+        bail!("Usage: <command> &");
+    }
+
+    let original = mem::replace(&mut config.current_toolchain, toolchain.clone());
+    let command = cargo_command(config, &args);
+    config.current_toolchain = original;
+
+    match command {
+        Some(command) => {
+            config.jobs.spawn(command, cmd.into(), toolchain)?;
+            Ok(Outcome::ok())
+        },
+        None => Ok(Outcome::failed()),
+    }
+}
+
+/// The status for `exit` to exit with: the one it was given, or else that of the last command.
+fn exit_status(config: &Config, arg: &str) -> Result<i32> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Ok(config.last_outcome.map(|o| o.status()).unwrap_or(0));
+    }
+    match arg.parse() {
+        Ok(status) => Ok(status),
+        Err(_) => bail!("Usage: exit [<status>]"),
+    }
+}
+
+/// Parses the job number given to `fg` or `kill`, which can be written as `N` or `%N`.
+fn job_id(arg: &str) -> Result<Option<usize>> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Ok(None);
+    }
+    let id = if arg.starts_with('%') { &arg[1..] } else { arg };
+    match id.parse() {
+        Ok(id) => Ok(Some(id)),
+        Err(_) => bail!("Invalid job number: {}", arg),
+    }
 }
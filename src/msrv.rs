@@ -0,0 +1,119 @@
+//! Checking that a crate still builds on its minimum supported Rust version, with `msrv`.
+//!
+//! The version comes from `package.rust-version` in the manifest of the package commands are run
+//! for, and the command that is run is `check`, unless `cargo-shell.msrv-command` says otherwise.
+//! With `--bisect`, the command is run on the installed toolchains that have version numbers to
+//! find the oldest one that actually works, assuming that anything newer works too.
+
+use std::time::Instant;
+
+use errors::*;
+use outcome::Outcome;
+use signals;
+use toolchain;
+use {run, words, Config};
+
+/// Runs the MSRV check, or bisects for the real minimum version if `bisect` is set.
+pub fn msrv(config: &mut Config, bisect: bool) -> Result<Outcome> {
+    let manifest = match config.package {
+        Some(ref package) => config.members.iter()
+                                           .find(|m| &m.name == package)
+                                           .map(|m| m.root.join("Cargo.toml")),
+        None => config.manifest.clone(),
+    };
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => bail!("`msrv` needs a cargo project, but there isn't one here"),
+    };
+    let declared = toolchain::rust_version(&manifest, &config.root.join("Cargo.toml"))?;
+    let args = words(config, &config.msrv_command)?;
+
+    if bisect {
+        return find_minimum(config, &args, declared);
+    }
+
+    let version = match declared {
+        Some(version) => version,
+        None => bail!("{} doesn't set `rust-version`, try `msrv --bisect` to find it", manifest.display()),
+    };
+    let installed = toolchain::installed(&config.rustup)?;
+    let name = toolchain::for_version(&installed, &version);
+    if !toolchain::is_installed(&installed, &name) {
+        bail!("The `{}` toolchain for rust-version {} is not installed, try `toolchain install {}`", name, version, name);
+    }
+
+    println!("Running `{}` with the MSRV toolchain `{}`", args.join(" "), name);
+    let outcome = run_with(config, &name, &args)?;
+    if outcome.success() {
+        println!("The crate builds with its rust-version, {}", version);
+    } else {
+        println!("The crate does not build with its rust-version, {}", version);
+    }
+    Ok(outcome)
+}
+
+/// Bisects the installed toolchains for the oldest one that `args` succeeds on.
+fn find_minimum(config: &mut Config, args: &[String], declared: Option<String>) -> Result<Outcome> {
+    let start = Instant::now();
+    let mut versions = toolchain::installed(&config.rustup)?
+        .iter()
+        .map(|t| toolchain::short_name(t).to_string())
+        .filter_map(|t| toolchain::version(&t).map(|v| (v, t)))
+        .collect::<Vec<_>>();
+    versions.sort();
+    versions.dedup_by_key(|v| v.0);
+    let versions = versions.into_iter().map(|(_, t)| t).collect::<Vec<_>>();
+    if versions.is_empty() {
+        bail!("There are no toolchains with version numbers installed, try `toolchain install 1.70`");
+    }
+
+    let _guard = signals::catch_interrupts();
+    let try_version = |config: &mut Config, name: &str| -> Result<bool> {
+        println!("Trying `{}`", name);
+        let outcome = run_with(config, name, args)?;
+        if signals::interrupted() {
+            bail!("interrupted");
+        }
+        Ok(outcome.success())
+    };
+
+    // the newest toolchain has to work, or there's nothing to bisect
+    let newest = versions.len() - 1;
+    if !try_version(config, &versions[newest])? {
+        println!("The crate does not build with any of the installed versions, the newest being {}", versions[newest]);
+        return Ok(Outcome { duration: start.elapsed(), ..Outcome::failed() });
+    }
+    let (mut low, mut high) = (0, newest);
+    while low < high {
+        let middle = (low + high) / 2;
+        if try_version(config, &versions[middle])? {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    let minimum = &versions[high];
+    if high == 0 {
+        println!("The crate builds with {}, the oldest installed version, so the real minimum might be older", minimum);
+    } else {
+        println!("The oldest installed version that builds the crate is {}", minimum);
+    }
+    match declared {
+        Some(ref version) if toolchain::version(version) == toolchain::version(minimum) => {
+            println!("That matches its rust-version");
+        },
+        Some(ref version) => println!("Its rust-version is {}", version),
+        None => println!("Its rust-version is not set"),
+    }
+    Ok(Outcome { duration: start.elapsed(), ..Outcome::ok() })
+}
+
+/// Runs `cargo <args>` on the toolchain `name`, going back to the active toolchain afterwards.
+fn run_with(config: &mut Config, name: &str, args: &[String]) -> Result<Outcome> {
+    let original = config.current_toolchain.clone();
+    config.current_toolchain = name.into();
+    let outcome = run(config, args);
+    config.current_toolchain = original;
+    outcome
+}
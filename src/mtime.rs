@@ -0,0 +1,32 @@
+//! Noticing when files the shell has read get changed.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The modification times of a set of files, at the time the snapshot was taken.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Snapshot {
+    /// Records the modification times of `files`. Files that don't exist are recorded too, so
+    /// that creating them counts as a change.
+    pub fn take(files: Vec<PathBuf>) -> Snapshot {
+        let files = files.into_iter().map(|f| {
+            let mtime = modified(&f);
+            (f, mtime)
+        }).collect();
+        Snapshot { files: files }
+    }
+
+    /// Whether any of the files has been created, changed or removed since the snapshot.
+    pub fn changed(&self) -> bool {
+        self.files.iter().any(|&(ref f, mtime)| modified(f) != mtime)
+    }
+}
+
+fn modified(file: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
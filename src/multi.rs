@@ -0,0 +1,246 @@
+//! Running a command under every toolchain in `cargo-shell.toolchains`, with `+`.
+//!
+//! The command is run under every toolchain even when some of them fail, unless `--fail-fast` is
+//! given, and a table of how each toolchain did is printed at the end. Ctrl-C stops the whole run.
+//!
+//! With `+&`, or the `cargo-shell.parallel-toolchains` setting, the toolchains run at the same
+//! time. Each of them builds into a target directory of its own so that they don't wait on each
+//! other for the build directory lock, and their output is captured and printed one toolchain at a
+//! time, in the order the toolchains are configured.
+
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use errors::*;
+use jobs;
+use outcome::{self, Outcome};
+use process::{self, Interrupts};
+use signals;
+use {cargo_command, run, Config};
+
+/// A command running under one of the toolchains.
+struct Run {
+    toolchain: String,
+    child: Child,
+    output: Arc<Mutex<jobs::Output>>,
+    readers: Vec<thread::JoinHandle<()>>,
+    outcome: Option<Outcome>,
+}
+
+impl Run {
+    fn update(&mut self, start: Instant) -> Result<()> {
+        if self.outcome.is_none() {
+            if let Some(status) = self.child.try_wait().chain_err(|| format!("Could not check on `{}`", self.toolchain))? {
+                self.outcome = Some(Outcome::from_status(status, start.elapsed()));
+            }
+        }
+        Ok(())
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        // every run leads its own process group, so this reaches the `rustc`s it started too
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), signal);
+        }
+    }
+
+    /// Prints everything the command wrote, once it has finished.
+    fn print(&mut self) {
+        // the readers finish when the command closes its end of the pipes
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+        println!("Running command with toolchain `{}`", self.toolchain);
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(&self.output.lock().unwrap().bytes);
+        let _ = stdout.flush();
+    }
+}
+
+impl Drop for Run {
+    /// Makes sure nothing is left running when the other runs fail to start, or something goes wrong
+    /// while waiting for them.
+    fn drop(&mut self) {
+        if self.outcome.is_none() {
+            self.signal(libc::SIGKILL);
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Splits the `--fail-fast` flag off the front of the arguments to `+`.
+pub fn fail_fast(args: &mut Vec<String>) -> bool {
+    if args.first().map(|a| a == "--fail-fast").unwrap_or(false) {
+        args.remove(0);
+        true
+    } else {
+        false
+    }
+}
+
+/// Runs `cargo <args>` under every configured toolchain, one after the other. With `fail_fast`,
+/// this stops at the first toolchain that fails.
+pub fn run_sequential(config: &mut Config, args: &[String], fail_fast: bool) -> Result<Outcome> {
+    check_toolchains(config)?;
+    let start = Instant::now();
+    let _guard = signals::catch_interrupts();
+    let original = config.current_toolchain.clone();
+    let mut results = Vec::new();
+
+    for toolchain in config.toolchains.clone() {
+        config.current_toolchain = toolchain.clone();
+        println!("Running command with toolchain `{}`", toolchain);
+        let outcome = match run(config, args) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                println!("Error: {:?}", e);
+                Outcome::failed()
+            },
+        };
+        results.push((toolchain, outcome));
+        if signals::interrupted() || (fail_fast && !outcome.success()) {
+            break;
+        }
+    }
+    config.current_toolchain = original;
+
+    print_summary(&results);
+    Ok(overall(&results, start))
+}
+
+/// Runs `cargo <args>` under every configured toolchain at once. With `fail_fast`, the other
+/// toolchains are interrupted as soon as one of them fails.
+pub fn run_parallel(config: &mut Config, args: &[String], fail_fast: bool) -> Result<Outcome> {
+    check_toolchains(config)?;
+    let start = Instant::now();
+    let _guard = signals::catch_interrupts();
+    let mut interrupts = Interrupts::start();
+
+    let mut runs = Vec::new();
+    for toolchain in config.toolchains.clone() {
+        let original = mem::replace(&mut config.current_toolchain, toolchain.clone());
+        let command = cargo_command(config, args);
+        let target = target_dir(config);
+        config.current_toolchain = original;
+        let mut command = match command {
+            Some(command) => command,
+            None => return Ok(Outcome::failed()),
+        };
+
+        command.env("CARGO_TARGET_DIR", target)
+               .stdin(Stdio::null())
+               .stdout(Stdio::piped())
+               .stderr(Stdio::piped());
+        process::own_process_group(&mut command);
+        let mut child = command.spawn().chain_err(|| format!("Could not execute rustup run command for `{}`", toolchain))?;
+
+        // all of it is printed in the end, so none of it can be dropped
+        let output = Arc::new(Mutex::new(jobs::Output::new(usize::MAX)));
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(jobs::capture(stdout, output.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(jobs::capture(stderr, output.clone()));
+        }
+        runs.push(Run {
+            toolchain: toolchain,
+            child: child,
+            output: output,
+            readers: readers,
+            outcome: None,
+        });
+    }
+    println!("Running command with toolchains {}",
+             runs.iter().map(|r| &r.toolchain[..]).collect::<Vec<_>>().join(", "));
+
+    // the output of a toolchain is printed once it and every toolchain before it have finished
+    let mut printed = 0;
+    let mut stopping = false;
+    while printed < runs.len() {
+        for r in &mut runs {
+            r.update(start)?;
+        }
+        if fail_fast && !stopping && runs.iter().any(|r| r.outcome.map(|o| !o.success()).unwrap_or(false)) {
+            for r in runs.iter().filter(|r| r.outcome.is_none()) {
+                r.signal(libc::SIGINT);
+            }
+            stopping = true;
+        }
+        while printed < runs.len() && runs[printed].outcome.is_some() {
+            runs[printed].print();
+            printed += 1;
+        }
+
+        if let Some(signal) = interrupts.check() {
+            for r in runs.iter().filter(|r| r.outcome.is_none()) {
+                r.signal(signal);
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    if interrupts.any() {
+        println!("interrupted");
+    }
+
+    let results = runs.iter()
+                      .map(|r| (r.toolchain.clone(), r.outcome.unwrap_or_else(Outcome::failed)))
+                      .collect::<Vec<_>>();
+    print_summary(&results);
+    Ok(overall(&results, start))
+}
+
+/// Makes sure there is something to run on, since none of the toolchains being valid would
+/// otherwise look like every one of them succeeding.
+fn check_toolchains(config: &Config) -> Result<()> {
+    if config.toolchains.is_empty() {
+        bail!("There are no toolchains to run on, check the `cargo-shell.toolchains` setting");
+    }
+    Ok(())
+}
+
+/// The outcome of the whole run: the first toolchain that failed, if any did.
+fn overall(results: &[(String, Outcome)], start: Instant) -> Outcome {
+    match results.iter().find(|&&(_, ref o)| !o.success()) {
+        Some(&(_, outcome)) => outcome,
+        None => Outcome { duration: start.elapsed(), ..Outcome::ok() },
+    }
+}
+
+/// Prints a table of how the command did under each toolchain.
+pub fn print_summary(results: &[(String, Outcome)]) {
+    let width = results.iter().map(|&(ref t, _)| t.len()).max().unwrap_or(0).max("toolchain".len());
+    println!();
+    println!("{:<width$}  {:<6}  {:>10}  {}", "toolchain", "result", "time", "status", width = width);
+    for &(ref toolchain, ref outcome) in results {
+        let status = match (outcome.code, outcome.signal) {
+            (Some(code), _) => code.to_string(),
+            (None, Some(signal)) => format!("signal {}", signal),
+            (None, None) => String::new(),
+        };
+        println!("{:<width$}  {:<6}  {:>10}  {}",
+                 toolchain,
+                 if outcome.success() { "ok" } else { "FAILED" },
+                 outcome::format_duration(outcome.duration),
+                 status,
+                 width = width);
+    }
+}
+
+/// Where the build for the active toolchain goes, so that each toolchain gets a target directory of
+/// its own inside the one the command would have used.
+fn target_dir(config: &Config) -> PathBuf {
+    let base = match config.command_var("CARGO_TARGET_DIR") {
+        Some(dir) => config.cwd.join(dir),
+        None => config.root.join("target"),
+    };
+    base.join("cargo-shell").join(config.current_toolchain.replace('/', "_"))
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// How a command finished, and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration: Duration,
+}
+
+impl Outcome {
+    /// The outcome of a command that didn't spawn anything, like a shell built-in.
+    pub fn ok() -> Outcome {
+        Outcome {
+            code: Some(0),
+            signal: None,
+            duration: Duration::from_secs(0),
+        }
+    }
+
+    /// A failed outcome for a shell built-in that couldn't do what it was asked.
+    pub fn failed() -> Outcome {
+        Outcome {
+            code: Some(1),
+            signal: None,
+            duration: Duration::from_secs(0),
+        }
+    }
+
+    pub fn from_status(status: ExitStatus, duration: Duration) -> Outcome {
+        Outcome {
+            code: status.code(),
+            signal: signal(&status),
+            duration: duration,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// The exit status a shell would report for the command.
+    pub fn status(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            // the same number a shell would use for a process killed by a signal
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exited with status {}", code)?,
+            (None, Some(signal)) => write!(f, "killed by signal {}", signal)?,
+            (None, None) => write!(f, "exited")?,
+        }
+        write!(f, " after {}", format_duration(self.duration))
+    }
+}
+
+/// Formats a duration as seconds with two decimal places, e.g. `12.34s`.
+pub fn format_duration(d: Duration) -> String {
+    format!("{}.{:02}s", d.as_secs(), d.subsec_nanos() / 10_000_000)
+}
+
+#[cfg(unix)]
+fn signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
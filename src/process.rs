@@ -0,0 +1,156 @@
+//! Running a command in the foreground, in a way that Ctrl-C stops the command but not the shell.
+//!
+//! The command gets a process group of its own, so that the shell can signal it along with every
+//! `rustc` or test binary it started. Ctrl-C sends it `SIGINT`, and a second Ctrl-C soon after
+//! sends `SIGKILL`, for commands that don't stop when asked nicely. If the command tries to read
+//! from the terminal, it is given control of the terminal until it exits.
+
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use errors::*;
+use outcome::Outcome;
+use signals;
+
+/// A second Ctrl-C within this long of the first kills the command outright
+pub const KILL_WINDOW: Duration = Duration::from_secs(2);
+
+/// Makes `cmd` start in a process group of its own, so that it can be signalled along with
+/// everything it starts.
+pub fn own_process_group(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setpgid(0, 0);
+            Ok(())
+        });
+    }
+}
+
+/// Turns Ctrl-C presses into the signal to send to a command: `SIGINT` at first, and `SIGKILL`
+/// for a press within `KILL_WINDOW` of the one before it.
+pub struct Interrupts {
+    count: usize,
+    first: usize,
+    last: Option<Instant>,
+}
+
+impl Interrupts {
+    /// Starts watching for Ctrl-C, ignoring any presses from before now.
+    pub fn start() -> Interrupts {
+        let count = signals::interrupt_count();
+        Interrupts {
+            count: count,
+            first: count,
+            last: None,
+        }
+    }
+
+    /// The signal to send for Ctrl-C having been pressed since the last check, if it was.
+    pub fn check(&mut self) -> Option<libc::c_int> {
+        let count = signals::interrupt_count();
+        if count <= self.count {
+            return None;
+        }
+        let now = Instant::now();
+        let signal = match self.last {
+            Some(last) if now.duration_since(last) < KILL_WINDOW => libc::SIGKILL,
+            // pressed twice between checks
+            _ if count - self.count > 1 => libc::SIGKILL,
+            _ => libc::SIGINT,
+        };
+        self.count = count;
+        self.last = Some(now);
+        Some(signal)
+    }
+
+    /// Whether Ctrl-C has been pressed since watching started.
+    pub fn any(&self) -> bool {
+        self.count > self.first
+    }
+}
+
+/// Runs `cmd` to completion, returning how it finished.
+pub fn run_foreground(cmd: &mut Command) -> Result<Outcome> {
+    let start = Instant::now();
+
+    // without a terminal there's nobody to press Ctrl-C, so there's nothing special to do
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        let status = cmd.status().chain_err(|| "Could not execute rustup run command")?;
+        return Ok(Outcome::from_status(status, start.elapsed()));
+    }
+
+    own_process_group(cmd);
+    let _guard = signals::catch_interrupts();
+    let child = cmd.spawn().chain_err(|| "Could not execute rustup run command")?;
+    let pid = child.id() as libc::pid_t;
+    // the child does this too, but whichever of us gets there first wins the race with `kill`
+    unsafe {
+        libc::setpgid(pid, pid);
+    }
+
+    // when running under `~`, Ctrl-C may already have been pressed before this command started
+    let mut interrupts = Interrupts::start();
+    let mut has_terminal = false;
+
+    let status = loop {
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG | libc::WUNTRACED) };
+        if ret == pid {
+            if is_stopped(status) {
+                // stopped by SIGTTIN or SIGTTOU, because it wants to use the terminal
+                if !has_terminal {
+                    unsafe {
+                        libc::tcsetpgrp(libc::STDIN_FILENO, pid);
+                    }
+                    has_terminal = true;
+                }
+                unsafe {
+                    libc::kill(-pid, libc::SIGCONT);
+                }
+                continue;
+            }
+            break status;
+        } else if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err).chain_err(|| "Could not wait for command to finish");
+            }
+        }
+
+        if let Some(signal) = interrupts.check() {
+            debug!("sending signal {} to process group {}", signal, pid);
+            unsafe {
+                libc::kill(-pid, signal);
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    if has_terminal {
+        take_terminal();
+    }
+    if interrupts.any() {
+        println!("interrupted");
+    }
+    Ok(Outcome::from_status(ExitStatus::from_raw(status), start.elapsed()))
+}
+
+/// Makes the shell the foreground process group of the terminal again.
+fn take_terminal() {
+    unsafe {
+        // a background process group gets SIGTTOU for trying this, unless it is ignored
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+/// The `WIFSTOPPED` macro, which not every version of the libc crate has.
+fn is_stopped(status: libc::c_int) -> bool {
+    status & 0xff == 0x7f
+}
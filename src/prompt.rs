@@ -0,0 +1,248 @@
+//! The template language for the prompt.
+//!
+//! A template is plain text with these kinds of `{...}` tags in it:
+//!
+//!   * `{name}` is replaced with the value of the variable `name`, like `{project}` or `{branch}`
+//!   * `{red}`, `{bold}`, `{reset}` and the other names in `STYLES` change the color or style of
+//!     the text that follows
+//!   * `{?name:text}` renders `text` only if `name` is set to something other than `""` or `"0"`,
+//!     and `text` can contain tags of its own, like `{?dirty:{red}{dirty}{reset}}`
+//!   * `{{` and `}}` are literal braces, except that inside a conditional `}` always ends it, so
+//!     `{?ahead:{?behind:!}}` is two nested conditionals
+//!
+//! Tags with unknown names are left in the prompt as they are. Colors only ever produce SGR
+//! escape sequences (`ESC [ ... m`), which rustyline knows take up no space on the screen, and
+//! control characters are removed from variable values, so the cursor always ends up in the
+//! right place.
+
+use std::collections::HashMap;
+
+use errors::*;
+
+const STYLES: &'static [(&'static str, &'static str)] = &[
+    ("reset", "0"),
+    ("bold", "1"),
+    ("dim", "2"),
+    ("italic", "3"),
+    ("underline", "4"),
+    ("black", "30"),
+    ("red", "31"),
+    ("green", "32"),
+    ("yellow", "33"),
+    ("blue", "34"),
+    ("magenta", "35"),
+    ("cyan", "36"),
+    ("white", "37"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(String),
+    Style(&'static str),
+    Cond(String, Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut chars = template.chars().peekable();
+        let nodes = parse_nodes(&mut chars, false)?;
+        Ok(Template { nodes: nodes })
+    }
+
+    /// Renders the template, looking up variables in `vars`.
+    pub fn render(&self, vars: &HashMap<&'static str, String>) -> String {
+        let mut out = String::new();
+        let styled = render_nodes(&self.nodes, vars, &mut out);
+        if styled {
+            out.push_str("\x1b[0m");
+        }
+        out
+    }
+}
+
+fn parse_nodes<I>(chars: &mut ::std::iter::Peekable<I>, nested: bool) -> Result<Vec<Node>>
+    where I: Iterator<Item=char>
+{
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None if nested => bail!("unterminated `{{?...}}` in prompt"),
+            None => break,
+        };
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            },
+            // inside a conditional, `}` always ends it, so that nested ones can end with `}}`
+            '}' if nested => break,
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            },
+            '{' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text));
+                    text = String::new();
+                }
+                nodes.push(parse_tag(chars)?);
+            },
+            c => text.push(c),
+        }
+    }
+
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    Ok(nodes)
+}
+
+/// Parses a tag, after its opening `{` has been consumed.
+fn parse_tag<I>(chars: &mut ::std::iter::Peekable<I>) -> Result<Node>
+    where I: Iterator<Item=char>
+{
+    let conditional = chars.peek() == Some(&'?');
+    if conditional {
+        chars.next();
+    }
+
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some(':') if conditional => {
+                let body = parse_nodes(chars, true)?;
+                return Ok(Node::Cond(name, body));
+            },
+            Some('}') if !conditional => break,
+            Some(c) => name.push(c),
+            None => bail!("unterminated `{{{}{}` in prompt", if conditional { "?" } else { "" }, name),
+        }
+    }
+
+    match STYLES.iter().find(|&&(style, _)| style == name) {
+        Some(&(_, code)) => Ok(Node::Style(code)),
+        None => Ok(Node::Var(name)),
+    }
+}
+
+/// Renders `nodes` into `out`, returning whether any styles were used.
+fn render_nodes(nodes: &[Node], vars: &HashMap<&'static str, String>, out: &mut String) -> bool {
+    let mut styled = false;
+    for node in nodes {
+        match *node {
+            Node::Text(ref text) => out.push_str(text),
+            Node::Var(ref name) => {
+                match vars.get(&name[..]) {
+                    Some(value) => out.extend(value.chars().filter(|c| !c.is_control())),
+                    None => {
+                        out.push('{');
+                        out.push_str(name);
+                        out.push('}');
+                    },
+                }
+            },
+            Node::Style(code) => {
+                out.push_str("\x1b[");
+                out.push_str(code);
+                out.push('m');
+                styled = true;
+            },
+            Node::Cond(ref name, ref body) => {
+                let set = match vars.get(&name[..]) {
+                    Some(value) => !value.is_empty() && value != "0",
+                    None => false,
+                };
+                if set {
+                    styled |= render_nodes(body, vars, out);
+                }
+            },
+        }
+    }
+    styled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, vars: &[(&'static str, &str)]) -> String {
+        let vars = vars.iter().map(|&(name, value)| (name, value.to_string())).collect();
+        Template::parse(template).unwrap().render(&vars)
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(render("{project}> ", &[("project", "foo")]), "foo> ");
+        assert_eq!(render("[{project}@{toolchain}]", &[("project", "foo"), ("toolchain", "stable")]),
+                   "[foo@stable]");
+        assert_eq!(render(">> ", &[]), ">> ");
+    }
+
+    #[test]
+    fn unknown_names_are_left_alone() {
+        assert_eq!(render("{nope}> ", &[]), "{nope}> ");
+    }
+
+    #[test]
+    fn control_characters_are_removed() {
+        assert_eq!(render("{branch}", &[("branch", "ma\x1b[2Jster\n")]), "ma[2Jster");
+    }
+
+    #[test]
+    fn styles() {
+        assert_eq!(render("{red}x{reset}", &[]), "\x1b[31mx\x1b[0m\x1b[0m");
+        assert_eq!(render("{bold}{green}>", &[]), "\x1b[1m\x1b[32m>\x1b[0m");
+    }
+
+    #[test]
+    fn literal_braces() {
+        assert_eq!(render("{{project}}", &[("project", "foo")]), "{project}");
+        assert_eq!(render("}", &[]), "}");
+    }
+
+    #[test]
+    fn conditionals() {
+        let template = "{?dirty:*}";
+        assert_eq!(render(template, &[("dirty", "1")]), "*");
+        assert_eq!(render(template, &[("dirty", "0")]), "");
+        assert_eq!(render(template, &[("dirty", "")]), "");
+        assert_eq!(render(template, &[]), "");
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let template = "{project}{?branch: ({branch}{?dirty:{red}*{reset}})}> ";
+        assert_eq!(render(template, &[("project", "foo"), ("branch", "master"), ("dirty", "1")]),
+                   "foo (master\x1b[31m*\x1b[0m)> \x1b[0m");
+        assert_eq!(render(template, &[("project", "foo"), ("branch", "master"), ("dirty", "0")]),
+                   "foo (master)> ");
+        assert_eq!(render(template, &[("project", "foo")]), "foo> ");
+    }
+
+    #[test]
+    fn braces_at_the_end_of_conditionals() {
+        // the `}` ending a tag or a conditional, followed by the `}` ending the conditional around
+        // it, isn't a literal `}}`
+        assert_eq!(render("{?ahead:{ahead}}", &[("ahead", "2")]), "2");
+        assert_eq!(render("{?ahead:{?behind:x}}", &[("ahead", "1"), ("behind", "1")]), "x");
+        assert_eq!(render("{?ahead:{?behind:x}}", &[("ahead", "1")]), "");
+        assert_eq!(render("{?ahead:{{}", &[("ahead", "1")]), "{");
+        assert_eq!(render("{?ahead:a}}", &[("ahead", "1")]), "a}");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Template::parse("{project").is_err());
+        assert!(Template::parse("{?dirty:*").is_err());
+        assert!(Template::parse("{?dirty:{red}*").is_err());
+    }
+}
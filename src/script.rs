@@ -0,0 +1,118 @@
+//! Running commands from a file with the `<` command.
+//!
+//! Every line of a script goes through `dispatch_cmd`, so special commands work the same way they
+//! do at the prompt. Scripts stop at the first command that fails, unless `set +e` is used to turn
+//! that off (and `set -e` to turn it back on). Those have to be on a line of their own, and the
+//! `set` built-in rejects them anywhere else. Scripts can include other scripts with `<`; relative
+//! paths are resolved against the directory of the including script.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use errors::*;
+use lexer;
+use outcome::Outcome;
+use {dispatch_cmd, Config};
+
+/// Runs every command in the script at `path`.
+pub fn run_file(config: &mut Config, path: &Path) -> Result<Outcome> {
+    let path = resolve(config, path);
+    let path = path.canonicalize().chain_err(|| format!("Could not open filename {}", path.display()))?;
+
+    if config.scripts.contains(&path) {
+        let chain = config.scripts.iter()
+                                  .chain(Some(&path))
+                                  .map(|p| p.display().to_string())
+                                  .collect::<Vec<_>>();
+        bail!("Script include cycle: {}", chain.join(" -> "));
+    }
+
+    config.scripts.push(path.clone());
+    let outcome = run_lines(config, &path);
+    config.scripts.pop();
+    outcome
+}
+
+/// Relative paths are relative to the script doing the including, or to the shell's working
+/// directory for scripts run from the prompt.
+fn resolve(config: &Config, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.into();
+    }
+    match config.scripts.last().and_then(|s| s.parent()) {
+        Some(dir) => dir.join(path),
+        None => config.cwd.join(path),
+    }
+}
+
+fn run_lines(config: &mut Config, path: &Path) -> Result<Outcome> {
+    let file = File::open(path).chain_err(|| format!("Could not open filename {}", path.display()))?;
+    run_reader(config, BufReader::new(file), &path.display().to_string())
+}
+
+/// Runs every command read from `reader`, using `name` for the source in messages.
+pub fn run_reader<R: BufRead>(config: &mut Config, reader: R, name: &str) -> Result<Outcome> {
+    let mut lines = reader.lines();
+    let mut lineno = 0;
+    let mut stop_on_failure = true;
+    let mut last = Outcome::ok();
+
+    while let Some(line) = lines.next() {
+        let mut line = line.chain_err(|| "Could not get next line from file")?;
+        lineno += 1;
+        let start = lineno;
+
+        // checked before looking for continuations, so that a comment like `# don't` doesn't
+        // swallow the lines after it
+        if line.trim() == "" || line.trim().starts_with("#") {
+            continue;
+        }
+
+        // a command can continue onto the following lines
+        while lexer::is_incomplete(&line) {
+            match lines.next() {
+                Some(more) => {
+                    line.push('\n');
+                    line.push_str(&more.chain_err(|| "Could not get next line from file")?);
+                    lineno += 1;
+                },
+                None => break,
+            }
+        }
+
+        let line = line.trim();
+        if line == "set -e" {
+            stop_on_failure = true;
+            continue;
+        } else if line == "set +e" {
+            stop_on_failure = false;
+            continue;
+        }
+
+        let outcome = match dispatch_cmd(config, line) {
+            Ok(outcome) => {
+                // for an `exit` further down
+                config.last_outcome = Some(outcome);
+                outcome
+            },
+            Err(e) if !stop_on_failure => {
+                println!("{}:{}: `{}` failed", name, start, line);
+                println!("Error: {:?}", e);
+                last = Outcome::failed();
+                config.last_outcome = Some(last);
+                continue;
+            },
+            Err(e) => return Err(e).chain_err(|| format!("{}:{}: `{}` failed", name, start, line)),
+        };
+        if !outcome.success() {
+            println!("{}:{}: `{}` {}", name, start, line, outcome);
+            if stop_on_failure {
+                return Ok(outcome);
+            }
+        }
+        last = outcome;
+    }
+
+    Ok(last)
+}
@@ -0,0 +1,49 @@
+//! Catching Ctrl-C while the shell is busy running something.
+//!
+//! At the prompt rustyline takes care of Ctrl-C, but anywhere else the default action of `SIGINT`
+//! would take the whole shell down with the command it interrupted.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Catches `SIGINT` until it is dropped, when the previous handler is put back.
+pub struct InterruptGuard {
+    previous: libc::sighandler_t,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
+
+/// Starts catching `SIGINT`, so that `interrupted` can be used to check for Ctrl-C. When this is
+/// nested inside another guard, earlier presses are still counted, so that the outer loop (like
+/// `~`) sees a Ctrl-C that interrupted the command it was running.
+pub fn catch_interrupts() -> InterruptGuard {
+    let handler = handle_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let previous = unsafe { libc::signal(libc::SIGINT, handler) };
+    if previous != handler {
+        INTERRUPTS.store(0, Ordering::SeqCst);
+    }
+    InterruptGuard { previous: previous }
+}
+
+/// Whether Ctrl-C has been pressed since `catch_interrupts` was called.
+pub fn interrupted() -> bool {
+    interrupt_count() > 0
+}
+
+/// How many times Ctrl-C has been pressed since `catch_interrupts` was called.
+pub fn interrupt_count() -> usize {
+    INTERRUPTS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,385 @@
+//! Finding out about the toolchains that rustup knows about, and pinning a project to one.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use toml;
+
+use errors::*;
+
+/// The toolchain to use if rustup doesn't have a default toolchain set.
+const FALLBACK_TOOLCHAIN: &'static str = "stable";
+
+/// Lists the toolchains installed by rustup, using their full names (including the host triple).
+pub fn installed(rustup: &Path) -> Result<Vec<String>> {
+    let output = Command::new(rustup).arg("toolchain")
+                                     .arg("list")
+                                     .output()
+                                     .chain_err(|| "Could not execute rustup toolchain list")?;
+    if !output.status.success() {
+        bail!("`rustup toolchain list` failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.starts_with("no installed toolchains") {
+        return Ok(Vec::new());
+    }
+    // lines look like `stable-x86_64-unknown-linux-gnu (default)`
+    Ok(stdout.lines()
+             .filter_map(|line| line.split_whitespace().next())
+             .map(String::from)
+             .collect())
+}
+
+/// Strips the host triple from a toolchain name, so `nightly-2017-01-01-x86_64-unknown-linux-gnu`
+/// becomes `nightly-2017-01-01`.
+pub fn short_name(name: &str) -> &str {
+    let parts = name.split('-').collect::<Vec<_>>();
+    let len = if is_date(&parts[1..]) {
+        parts[..4].iter().map(|p| p.len() + 1).sum::<usize>() - 1
+    } else {
+        parts[0].len()
+    };
+    &name[..len]
+}
+
+/// Whether `parts` start with a date, like `["2017", "01", "01", ...]`.
+fn is_date(parts: &[&str]) -> bool {
+    parts.len() >= 3 &&
+    parts[0].len() == 4 && parts[1].len() == 2 && parts[2].len() == 2 &&
+    parts[..3].iter().all(|p| p.chars().all(|c| c.is_digit(10)))
+}
+
+/// Whether `s` is a Rust version number, like `1.70` or `1.70.0`.
+fn is_version(s: &str) -> bool {
+    let parts = s.split('.').collect::<Vec<_>>();
+    (parts.len() == 2 || parts.len() == 3) &&
+    parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_digit(10)))
+}
+
+/// Parses a version number like `1.70` or `1.70.0` into its parts, where a missing patch number
+/// is 0.
+pub fn version(s: &str) -> Option<(u32, u32, u32)> {
+    if !is_version(s) {
+        return None;
+    }
+    let mut parts = s.split('.').map(|p| p.parse().unwrap_or(0));
+    Some((parts.next().unwrap_or(0), parts.next().unwrap_or(0), parts.next().unwrap_or(0)))
+}
+
+/// Whether `name` is something rustup could install: a channel (`stable`, `beta` or `nightly`) or a
+/// version number, optionally followed by a date and a host triple.
+pub fn is_valid_name(name: &str) -> bool {
+    let parts = name.split('-').collect::<Vec<_>>();
+    let channel = ["stable", "beta", "nightly"].contains(&parts[0]) || is_version(parts[0]);
+    let rest = if is_date(&parts[1..]) { &parts[4..] } else { &parts[1..] };
+    channel && rest.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// Whether the installed toolchain `full` is the one called `name`, with or without the host
+/// triple.
+pub fn matches(full: &str, name: &str) -> bool {
+    full == name || short_name(full) == name
+}
+
+/// Whether `name` is one of the `installed` toolchains.
+pub fn is_installed(installed: &[String], name: &str) -> bool {
+    installed.iter().any(|t| matches(t, name))
+}
+
+/// Picks the toolchain to use for the Rust version `version`. A version without a patch number,
+/// like `1.70`, can be satisfied by an installed `1.70.x` toolchain.
+pub fn for_version(installed: &[String], version: &str) -> String {
+    if is_installed(installed, version) {
+        return version.into();
+    }
+    let prefix = format!("{}.", version);
+    installed.iter()
+             .map(|t| short_name(t))
+             .filter(|t| t.starts_with(&prefix) && is_version(t))
+             .last()
+             .unwrap_or(version)
+             .into()
+}
+
+/// Checks the entries of `cargo-shell.toolchains` against the `installed` toolchains, where `msrv`
+/// stands for the toolchain of the project's `rust-version`. Returns the toolchains that can be
+/// used, and a warning for each entry that can't.
+pub fn validate(entries: &[String], installed: &[String], rust_version: Option<&str>) -> (Vec<String>, Vec<String>) {
+    let mut toolchains = Vec::new();
+    let mut warnings = Vec::new();
+    for entry in entries {
+        let name = if entry == "msrv" {
+            match rust_version {
+                Some(version) => for_version(installed, version),
+                None => {
+                    warnings.push("`msrv` needs the project to set `rust-version` in its Cargo.toml".into());
+                    continue;
+                },
+            }
+        } else {
+            entry.clone()
+        };
+
+        if is_installed(installed, &name) {
+            if !toolchains.contains(&name) {
+                toolchains.push(name);
+            }
+        } else if is_valid_name(&name) {
+            warnings.push(format!("the `{}` toolchain is not installed, try `toolchain install {}`", name, name));
+        } else {
+            warnings.push(format!("`{}` is not a toolchain name", entry));
+        }
+    }
+    (toolchains, warnings)
+}
+
+/// Reads `package.rust-version` from `manifest`, following `rust-version.workspace = true` to
+/// `root_manifest`.
+pub fn rust_version(manifest: &Path, root_manifest: &Path) -> Result<Option<String>> {
+    let toml = match parse_toml(manifest)? {
+        Some(toml) => toml,
+        None => return Ok(None),
+    };
+    match toml.lookup("package.rust-version") {
+        Some(&toml::Value::String(ref version)) => Ok(Some(version.clone())),
+        Some(value) if value.lookup("workspace").and_then(|w| w.as_bool()) == Some(true) => {
+            let root = parse_toml(root_manifest)?;
+            Ok(root.as_ref()
+                   .and_then(|r| r.lookup("workspace.package.rust-version"))
+                   .and_then(|v| v.as_str())
+                   .map(String::from))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Writes a `rust-toolchain.toml` file in `dir` that pins it to `toolchain`, returning the path of
+/// the file.
+pub fn pin(dir: &Path, toolchain: &str) -> Result<PathBuf> {
+    let file = dir.join("rust-toolchain.toml");
+    File::create(&file).and_then(|mut f| write!(f, "[toolchain]\nchannel = \"{}\"\n", toolchain))
+                       .chain_err(|| format!("Could not write {}", file.display()))?;
+    Ok(file)
+}
+
+/// Where the default toolchain for the shell came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The `cargo-shell.default-toolchain` configuration option
+    Config,
+    /// The `RUSTUP_TOOLCHAIN` environment variable
+    Environment,
+    /// A directory override set with `rustup override set`
+    Override(PathBuf),
+    /// A `rust-toolchain` or `rust-toolchain.toml` file
+    ToolchainFile(PathBuf),
+    /// The toolchain set with `rustup default`
+    RustupDefault,
+    /// Nothing was configured anywhere, so we guessed
+    Fallback,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Config => write!(f, "the cargo-shell.default-toolchain setting"),
+            Source::Environment => write!(f, "the RUSTUP_TOOLCHAIN environment variable"),
+            Source::Override(ref dir) => write!(f, "the rustup override for {}", dir.display()),
+            Source::ToolchainFile(ref file) => write!(f, "{}", file.display()),
+            Source::RustupDefault => write!(f, "the rustup default toolchain"),
+            Source::Fallback => write!(f, "no configured toolchain, using the fallback"),
+        }
+    }
+}
+
+/// Finds the toolchain that rustup would use in `cwd`: the `RUSTUP_TOOLCHAIN` environment
+/// variable, then overrides and toolchain files in `cwd` and its parents, then the rustup default.
+pub fn resolve(cwd: &Path) -> Result<(String, Source)> {
+    if let Ok(toolchain) = env::var("RUSTUP_TOOLCHAIN") {
+        if !toolchain.is_empty() {
+            return Ok((toolchain, Source::Environment));
+        }
+    }
+
+    let settings = match rustup_home() {
+        Some(home) => parse_toml(&home.join("settings.toml"))?,
+        None => None,
+    };
+    let overrides = settings.as_ref().and_then(|s| s.lookup("overrides")).and_then(|o| o.as_table());
+
+    let mut dir = Some(cwd);
+    while let Some(d) = dir {
+        if let Some(toolchain) = overrides.and_then(|o| o.get(&*d.to_string_lossy())).and_then(|t| t.as_str()) {
+            return Ok((toolchain.into(), Source::Override(d.into())));
+        }
+        for name in &["rust-toolchain", "rust-toolchain.toml"] {
+            let file = d.join(name);
+            if let Some(toolchain) = read_toolchain_file(&file)? {
+                return Ok((toolchain, Source::ToolchainFile(file)));
+            }
+        }
+        dir = d.parent();
+    }
+
+    match settings.as_ref().and_then(|s| s.lookup("default_toolchain")).and_then(|t| t.as_str()) {
+        Some(toolchain) => Ok((toolchain.into(), Source::RustupDefault)),
+        None => Ok((FALLBACK_TOOLCHAIN.into(), Source::Fallback)),
+    }
+}
+
+/// Reads the channel out of a toolchain file, which is either just the name of a toolchain, or a
+/// TOML file with a `[toolchain]` table.
+fn read_toolchain_file(file: &Path) -> Result<Option<String>> {
+    if !file.is_file() {
+        return Ok(None);
+    }
+    if let Some(toml) = parse_toml(file)? {
+        return Ok(toml.lookup("toolchain.channel").and_then(|c| c.as_str()).map(String::from));
+    }
+    let contents = read_file(file)?;
+    Ok(contents.lines().map(|l| l.trim()).find(|l| !l.is_empty()).map(String::from))
+}
+
+fn rustup_home() -> Option<PathBuf> {
+    match env::var_os("RUSTUP_HOME") {
+        Some(home) => Some(home.into()),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".rustup")),
+    }
+}
+
+fn read_file(file: &Path) -> Result<String> {
+    let mut contents = String::new();
+    File::open(file).and_then(|mut f| f.read_to_string(&mut contents))
+                    .chain_err(|| format!("Could not read {}", file.display()))?;
+    Ok(contents)
+}
+
+/// Parses a TOML file, returning `None` if it doesn't exist or isn't valid TOML.
+fn parse_toml(file: &Path) -> Result<Option<toml::Value>> {
+    if !file.is_file() {
+        return Ok(None);
+    }
+    let contents = read_file(file)?;
+    Ok(toml::Parser::new(&contents).parse().map(toml::Value::Table))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_name("stable-x86_64-unknown-linux-gnu"), "stable");
+        assert_eq!(short_name("nightly-2017-01-01-x86_64-unknown-linux-gnu"), "nightly-2017-01-01");
+        assert_eq!(short_name("1.70.0-x86_64-apple-darwin"), "1.70.0");
+        assert_eq!(short_name("beta"), "beta");
+        assert_eq!(short_name("nightly-2017-01-01"), "nightly-2017-01-01");
+    }
+
+    fn installed() -> Vec<String> {
+        ["stable-x86_64-unknown-linux-gnu",
+         "nightly-2024-01-01-x86_64-unknown-linux-gnu",
+         "1.70.0-x86_64-unknown-linux-gnu",
+         "1.70.1-x86_64-unknown-linux-gnu"].iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn toolchains_for_versions() {
+        assert_eq!(for_version(&installed(), "1.70.0"), "1.70.0");
+        assert_eq!(for_version(&installed(), "1.70"), "1.70.1");
+        assert_eq!(for_version(&installed(), "1.7"), "1.7");
+        assert_eq!(for_version(&installed(), "1.71"), "1.71");
+    }
+
+    #[test]
+    fn validation() {
+        let entries = ["stable", "nightly-2024-01-01", "msrv", "beta", "not a toolchain", "stable"];
+        let entries = entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let (toolchains, warnings) = validate(&entries, &installed(), Some("1.70"));
+        assert_eq!(toolchains, ["stable", "nightly-2024-01-01", "1.70.1"]);
+        assert_eq!(warnings, ["the `beta` toolchain is not installed, try `toolchain install beta`",
+                              "`not a toolchain` is not a toolchain name"]);
+
+        let (toolchains, warnings) = validate(&["msrv".to_string()], &installed(), None);
+        assert!(toolchains.is_empty());
+        assert_eq!(warnings.len(), 1);
+
+        let (toolchains, warnings) = validate(&["msrv".to_string()], &installed(), Some("1.72"));
+        assert!(toolchains.is_empty());
+        assert_eq!(warnings, ["the `1.72` toolchain is not installed, try `toolchain install 1.72`"]);
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("stable"));
+        assert!(is_valid_name("1.70"));
+        assert!(is_valid_name("nightly-2024-01-01"));
+        assert!(is_valid_name("beta-x86_64-unknown-linux-gnu"));
+        assert!(!is_valid_name("stabel"));
+        assert!(!is_valid_name("1.70-"));
+        assert_eq!(version("1.70"), Some((1, 70, 0)));
+        assert_eq!(version("1.70.2"), Some((1, 70, 2)));
+        assert_eq!(version("1.70.2.1"), None);
+        assert_eq!(version("nightly"), None);
+    }
+
+    #[test]
+    fn resolve_like_rustup() {
+        // everything is in one test, because it has to change the environment
+        let dir = env::temp_dir().join(format!("cargo-shell-resolve-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let overridden = dir.join("overridden");
+        write(&dir.join("rustup").join("settings.toml"),
+              &format!("default_toolchain = \"stable-x86_64-unknown-linux-gnu\"\n\n\
+                        [overrides]\n\"{}\" = \"beta\"\n",
+                       overridden.display()));
+        write(&dir.join("toml").join("rust-toolchain.toml"), "[toolchain]\nchannel = \"nightly\"\n");
+        write(&dir.join("plain").join("rust-toolchain"), "\n1.70.0\n");
+        fs::create_dir_all(dir.join("toml").join("src")).unwrap();
+        fs::create_dir_all(overridden.join("src")).unwrap();
+        fs::create_dir_all(dir.join("none")).unwrap();
+
+        let saved = (env::var_os("RUSTUP_TOOLCHAIN"), env::var_os("RUSTUP_HOME"));
+        env::remove_var("RUSTUP_TOOLCHAIN");
+        env::set_var("RUSTUP_HOME", dir.join("rustup"));
+
+        assert_eq!(resolve(&dir.join("none")).unwrap(),
+                   ("stable-x86_64-unknown-linux-gnu".to_string(), Source::RustupDefault));
+        assert_eq!(resolve(&dir.join("toml").join("src")).unwrap(),
+                   ("nightly".to_string(), Source::ToolchainFile(dir.join("toml").join("rust-toolchain.toml"))));
+        assert_eq!(resolve(&dir.join("plain")).unwrap(),
+                   ("1.70.0".to_string(), Source::ToolchainFile(dir.join("plain").join("rust-toolchain"))));
+        assert_eq!(resolve(&overridden.join("src")).unwrap(),
+                   ("beta".to_string(), Source::Override(overridden.clone())));
+
+        env::set_var("RUSTUP_TOOLCHAIN", "1.75");
+        assert_eq!(resolve(&dir.join("toml")).unwrap(), ("1.75".to_string(), Source::Environment));
+        env::remove_var("RUSTUP_TOOLCHAIN");
+
+        env::set_var("RUSTUP_HOME", dir.join("missing"));
+        assert_eq!(resolve(&dir.join("none")).unwrap(), ("stable".to_string(), Source::Fallback));
+
+        match saved.0 {
+            Some(toolchain) => env::set_var("RUSTUP_TOOLCHAIN", toolchain),
+            None => env::remove_var("RUSTUP_TOOLCHAIN"),
+        }
+        match saved.1 {
+            Some(home) => env::set_var("RUSTUP_HOME", home),
+            None => env::remove_var("RUSTUP_HOME"),
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,359 @@
+//! The `~` command, which re-runs a command whenever a source file changes.
+//!
+//! For every workspace member this watches `src`, `tests`, `examples` and `benches` (including
+//! their subdirectories), `build.rs` and `Cargo.toml`, using inotify. Files matched by the
+//! `.gitignore` of the workspace or of a member are skipped, and the `Cargo.toml` of the workspace
+//! root is watched even when the root isn't a member itself. Changes that come in quick succession
+//! are collected into a single re-run, and the command is run through `dispatch_cmd`, so `~+test`
+//! and `~++nightly check` work too. Ctrl-C stops watching and goes back to the prompt.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use libc;
+
+use errors::*;
+use outcome::Outcome;
+use signals;
+use {dispatch_cmd, Config};
+
+/// How long to wait for more changes before re-running the command, in milliseconds
+const DEBOUNCE_MS: libc::c_int = 200;
+
+/// How often to check for Ctrl-C while waiting for changes, in milliseconds
+const POLL_MS: libc::c_int = 250;
+
+const WATCHED_DIRS: &'static [&'static str] = &["src", "tests", "examples", "benches"];
+const WATCHED_FILES: &'static [&'static str] = &["build.rs", "Cargo.toml"];
+
+// not every version of the libc crate has bindings for inotify
+mod ffi {
+    use libc::{c_char, c_int};
+
+    #[repr(C)]
+    pub struct inotify_event {
+        pub wd: c_int,
+        pub mask: u32,
+        pub cookie: u32,
+        pub len: u32,
+    }
+
+    pub const IN_NONBLOCK: c_int = 0o4000;
+    pub const IN_CLOEXEC: c_int = 0o2000000;
+
+    pub const IN_MODIFY: u32 = 0x0000_0002;
+    pub const IN_CLOSE_WRITE: u32 = 0x0000_0008;
+    pub const IN_MOVED_FROM: u32 = 0x0000_0040;
+    pub const IN_MOVED_TO: u32 = 0x0000_0080;
+    pub const IN_CREATE: u32 = 0x0000_0100;
+    pub const IN_DELETE: u32 = 0x0000_0200;
+    pub const IN_Q_OVERFLOW: u32 = 0x0000_4000;
+    pub const IN_ISDIR: u32 = 0x4000_0000;
+
+    extern "C" {
+        pub fn inotify_init1(flags: c_int) -> c_int;
+        pub fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+    }
+}
+
+const WATCH_MASK: u32 = ffi::IN_MODIFY | ffi::IN_CLOSE_WRITE | ffi::IN_MOVED_FROM | ffi::IN_MOVED_TO |
+                        ffi::IN_CREATE | ffi::IN_DELETE;
+
+/// Runs `cmd` once, and then again every time a watched file changes, until Ctrl-C is pressed.
+pub fn watch(config: &mut Config, cmd: &str, clear: bool) -> Result<Outcome> {
+    if cmd.is_empty() {
+        bail!("Usage: ~ <command>");
+    }
+
+    let mut watcher = Watcher::new()?;
+    let mut roots = config.members.iter().map(|m| m.root.clone()).collect::<Vec<_>>();
+    if roots.is_empty() {
+        roots.push(config.root.clone());
+    }
+    watcher.ignore.load(&config.root);
+    // a virtual workspace's own `Cargo.toml` isn't in any member
+    watcher.add(&config.root, false)?;
+    for root in &roots {
+        watcher.ignore.load(root);
+        watcher.add(root, false)?;
+        for dir in WATCHED_DIRS {
+            let dir = root.join(dir);
+            if dir.is_dir() {
+                watcher.add(&dir, true)?;
+            }
+        }
+    }
+
+    let _guard = signals::catch_interrupts();
+    let mut outcome = run(config, cmd, clear);
+
+    loop {
+        println!("Waiting for changes... Hit Ctrl-C to stop.");
+        let changed = loop {
+            if signals::interrupted() {
+                return outcome;
+            }
+            if watcher.wait(POLL_MS)? {
+                let mut changed = watcher.read()?;
+                // wait for things to settle down, since saving a file often touches it a few times
+                while watcher.wait(DEBOUNCE_MS)? {
+                    changed.extend(watcher.read()?);
+                }
+                if !changed.is_empty() {
+                    break changed;
+                }
+            }
+        };
+
+        if clear {
+            print!("\x1b[2J\x1b[H");
+        }
+        for path in &changed {
+            debug!("changed: {}", path.display());
+        }
+        println!("{} changed", changed[0].strip_prefix(&config.root).unwrap_or(&changed[0]).display());
+        outcome = run(config, cmd, false);
+        if signals::interrupted() {
+            return outcome;
+        }
+    }
+}
+
+fn run(config: &mut Config, cmd: &str, clear: bool) -> Result<Outcome> {
+    if clear {
+        print!("\x1b[2J\x1b[H");
+        let _ = io::stdout().flush();
+    }
+    // a failing command shouldn't stop the watching, so just report it
+    match dispatch_cmd(config, cmd) {
+        Ok(outcome) => {
+            if !outcome.success() {
+                println!("Command {}", outcome);
+            }
+            Ok(outcome)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            Ok(Outcome::failed())
+        },
+    }
+}
+
+struct Watcher {
+    fd: libc::c_int,
+    /// The directory for each watch descriptor, and whether it is watched recursively
+    dirs: HashMap<libc::c_int, (PathBuf, bool)>,
+    ignore: Ignore,
+}
+
+impl Watcher {
+    fn new() -> Result<Watcher> {
+        let fd = unsafe { ffi::inotify_init1(ffi::IN_NONBLOCK | ffi::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Could not initialize inotify");
+        }
+        Ok(Watcher {
+            fd: fd,
+            dirs: HashMap::new(),
+            ignore: Ignore::default(),
+        })
+    }
+
+    /// Watches `dir`, and all of its subdirectories if `recursive` is set.
+    fn add(&mut self, dir: &Path, recursive: bool) -> Result<()> {
+        if self.ignore.is_ignored(dir, true) {
+            return Ok(());
+        }
+        let path = CString::new(dir.as_os_str().as_bytes()).chain_err(|| format!("Invalid path {}", dir.display()))?;
+        let wd = unsafe { ffi::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| format!("Could not watch {}", dir.display()));
+        }
+        self.dirs.insert(wd, (dir.into(), recursive));
+
+        if recursive {
+            let entries = fs::read_dir(dir).chain_err(|| format!("Could not read directory {}", dir.display()))?;
+            for entry in entries.filter_map(|e| e.ok()) {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.add(&entry.path(), true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` milliseconds for events, returning whether there are any to read.
+    fn wait(&self, timeout: libc::c_int) -> Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            // Ctrl-C interrupts the poll, which the caller checks for
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err).chain_err(|| "Could not wait for file changes");
+        }
+        Ok(ready > 0)
+    }
+
+    /// Reads the pending events, returning the paths that changed and are worth re-running for.
+    fn read(&mut self) -> Result<Vec<PathBuf>> {
+        let mut buf = [0u8; 4096];
+        let mut changed = Vec::new();
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted {
+                    return Ok(changed);
+                }
+                return Err(err).chain_err(|| "Could not read file changes");
+            }
+
+            let len = len as usize;
+            let mut offset = 0;
+            let header = ::std::mem::size_of::<ffi::inotify_event>();
+            while offset + header <= len {
+                // the events in the buffer aren't necessarily aligned for `inotify_event`
+                let event = unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const ffi::inotify_event) };
+                let name = &buf[offset + header..offset + header + event.len as usize];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                offset += header + event.len as usize;
+
+                if event.mask & ffi::IN_Q_OVERFLOW != 0 {
+                    // we lost track of what changed, but something did
+                    changed.push(PathBuf::from("."));
+                    continue;
+                }
+                let (dir, recursive) = match self.dirs.get(&event.wd) {
+                    Some(&(ref dir, recursive)) => (dir.clone(), recursive),
+                    None => continue,
+                };
+                let path = dir.join(OsStr::from_bytes(name));
+                let is_dir = event.mask & ffi::IN_ISDIR != 0;
+
+                if !recursive {
+                    // the member's root directory, where only a few files are interesting
+                    let watched = WATCHED_FILES.iter().any(|f| OsStr::new(f).as_bytes() == name);
+                    if !watched {
+                        continue;
+                    }
+                } else if self.ignore.is_ignored(&path, is_dir) {
+                    continue;
+                }
+
+                if recursive && is_dir && event.mask & (ffi::IN_CREATE | ffi::IN_MOVED_TO) != 0 {
+                    self.add(&path, true)?;
+                }
+                changed.push(path);
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// The patterns from `.gitignore` files, which is enough of git's rules for deciding which
+/// changes to ignore. Negated patterns aren't supported.
+#[derive(Default)]
+struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    /// The directory of the `.gitignore` the pattern came from
+    base: PathBuf,
+    glob: String,
+    /// Patterns containing a `/` match against the whole path from `base`, not just the name
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Ignore {
+    fn load(&mut self, dir: &Path) {
+        let file = match File::open(dir.join(".gitignore")) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        for line in BufReader::new(file).lines().filter_map(|l| l.ok()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let dir_only = line.ends_with('/');
+            let glob = line.trim_matches('/');
+            self.patterns.push(Pattern {
+                base: dir.into(),
+                glob: glob.into(),
+                anchored: glob.contains('/') || line.starts_with('/'),
+                dir_only: dir_only,
+            });
+        }
+    }
+
+    /// Whether `path`, or any of the directories it is in, is ignored.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.patterns.iter().any(|p| {
+            let relative = match path.strip_prefix(&p.base) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            };
+            let components = relative.components()
+                                     .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                                     .collect::<Vec<_>>();
+            (0..components.len()).any(|i| {
+                // every component but the last is a directory
+                let last = i + 1 == components.len();
+                if p.dir_only && last && !is_dir {
+                    return false;
+                }
+                if p.anchored {
+                    glob_match(&p.glob, &components[..i + 1].join("/"))
+                } else {
+                    glob_match(&p.glob, &components[i])
+                }
+            })
+        })
+    }
+}
+
+/// Matches `text` against a glob with `*` and `?` wildcards.
+fn glob_match(glob: &str, text: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut g, mut t) = (0, 0);
+    // where to backtrack to after a `*` fails to match
+    let mut star = None;
+    while t < text.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == text[t]) && glob[g] != '*' {
+            g += 1;
+            t += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g, t));
+            g += 1;
+        } else if let Some((sg, st)) = star {
+            g = sg + 1;
+            t = st + 1;
+            star = Some((sg, st + 1));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}